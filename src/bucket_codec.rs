@@ -0,0 +1,178 @@
+use anyhow::{anyhow, Result};
+use std::{
+    fmt,
+    io::{self, BufReader, Read, Write},
+    str::FromStr,
+};
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// How records are encoded in the payload of a bucket file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BucketCodec {
+    /// Fixed-size little-endian fields.
+    #[default]
+    Raw = 0,
+    /// Each record as varint-encoded differences to the previous (sorted) record.
+    Delta = 1,
+    /// `Delta`, compressed with zstd.
+    DeltaZstd = 2,
+    /// `Delta`, compressed with lz4.
+    DeltaLz4 = 3,
+}
+
+impl BucketCodec {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Raw),
+            1 => Some(Self::Delta),
+            2 => Some(Self::DeltaZstd),
+            3 => Some(Self::DeltaLz4),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_delta(&self) -> bool {
+        *self != Self::Raw
+    }
+
+    /// Writes the payload through the compressor of this codec, if any.
+    pub fn compress<W, F>(&self, buffer: &mut W, write_payload: F) -> Result<()>
+    where
+        W: Write,
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+    {
+        match self {
+            Self::Raw | Self::Delta => write_payload(buffer),
+            Self::DeltaZstd => {
+                let mut encoder = zstd::Encoder::new(buffer, ZSTD_LEVEL)?;
+                write_payload(&mut encoder)?;
+                encoder.finish()?;
+                Ok(())
+            }
+            Self::DeltaLz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(buffer);
+                write_payload(&mut encoder)?;
+                encoder.finish()?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Wraps the payload reader with the decompressor of this codec, if any.
+    pub fn decompress<R: Read + Send + 'static>(&self, payload: R) -> Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Self::Raw | Self::Delta => Box::new(payload),
+            Self::DeltaZstd => Box::new(BufReader::new(zstd::Decoder::new(payload)?)),
+            Self::DeltaLz4 => Box::new(BufReader::new(lz4_flex::frame::FrameDecoder::new(payload))),
+        })
+    }
+}
+
+impl FromStr for BucketCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "delta" => Ok(Self::Delta),
+            "delta+zstd" => Ok(Self::DeltaZstd),
+            "delta+lz4" => Ok(Self::DeltaLz4),
+            _ => Err(anyhow!("Unknown bucket codec '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for BucketCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Raw => write!(f, "raw"),
+            Self::Delta => write!(f, "delta"),
+            Self::DeltaZstd => write!(f, "delta+zstd"),
+            Self::DeltaLz4 => write!(f, "delta+lz4"),
+        }
+    }
+}
+
+/// Writes an unsigned LEB128 varint.
+#[inline(always)]
+pub fn write_varint<W: Write>(buffer: &mut W, mut value: u64) -> io::Result<()> {
+    let mut bytes = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes[len] = byte;
+            len += 1;
+            break;
+        }
+        bytes[len] = byte | 0x80;
+        len += 1;
+    }
+    buffer.write_all(&bytes[..len])
+}
+
+/// Reads an unsigned LEB128 varint.
+#[inline(always)]
+pub fn read_varint<R: Read>(file_buffer: &mut R) -> io::Result<u64> {
+    let mut value = 0;
+    let mut byte = [0; 1];
+    for shift in (0..64).step_by(7) {
+        file_buffer.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Varint longer than 64 bits",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        let values = [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX];
+        let mut buffer = Vec::new();
+        for value in values {
+            write_varint(&mut buffer, value).unwrap();
+        }
+        assert_eq!(buffer[..5], [0, 1, 127, 0x80, 1]);
+        let mut reader = &buffer[..];
+        for value in values {
+            assert_eq!(read_varint(&mut reader).unwrap(), value);
+        }
+        assert!(read_varint(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        for codec in [
+            BucketCodec::Raw,
+            BucketCodec::Delta,
+            BucketCodec::DeltaZstd,
+            BucketCodec::DeltaLz4,
+        ] {
+            let mut compressed = Vec::new();
+            codec
+                .compress(&mut compressed, |buffer| {
+                    buffer.write_all(&[42; 1000])?;
+                    Ok(())
+                })
+                .unwrap();
+            let mut decompressed = Vec::new();
+            codec
+                .decompress(io::Cursor::new(compressed))
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, [42; 1000]);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::bucket_codec::BucketCodec;
+use anyhow::{anyhow, Result};
+use crc32fast::Hasher;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Take, Write},
+    path::Path,
+};
+
+/// Bucket file layout (all integers little-endian):
+///
+/// | bytes | content                              |
+/// |-------|--------------------------------------|
+/// | 4     | magic `RGBK`                         |
+/// | 2     | format version                       |
+/// | 1     | record type                          |
+/// | 1     | bases per kmer                       |
+/// | 1     | codec                                |
+/// | 4 + n | run parameters, length-prefixed UTF-8 |
+/// | 8     | entry count                          |
+/// | 8     | payload length in bytes              |
+/// | ...   | payload: the sorted records, encoded |
+/// | 4     | CRC32 of the payload                 |
+pub const MAGIC: [u8; 4] = *b"RGBK";
+pub const FORMAT_VERSION: u16 = 2;
+const CHECKSUM_SIZE: u64 = 4;
+
+/// The kind of record stored in a bucket file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecordType {
+    #[default]
+    KmerRead = 1,
+    ReadPairKmer = 2,
+}
+
+impl RecordType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::KmerRead),
+            2 => Some(Self::ReadPairKmer),
+            _ => None,
+        }
+    }
+}
+
+/// Written at the start of every bucket file.
+/// `parameters` describes the run that produced the file, so a file from an earlier run
+/// is only reused if it was created with the same input and settings.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BucketHeader {
+    record_type: RecordType,
+    bases_per_kmer: u8,
+    codec: BucketCodec,
+    parameters: String,
+    entry_count: u64,
+    payload_length: u64,
+}
+
+impl BucketHeader {
+    pub fn new(bases_per_kmer: usize, codec: BucketCodec, parameters: &str) -> Self {
+        Self {
+            bases_per_kmer: bases_per_kmer as u8,
+            codec,
+            parameters: parameters.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// A copy of this header for a file with the given contents.
+    pub fn for_file(&self, record_type: RecordType, entry_count: u64) -> Self {
+        Self {
+            record_type,
+            entry_count,
+            payload_length: 0,
+            ..self.clone()
+        }
+    }
+
+    pub fn bases_per_kmer(&self) -> usize {
+        self.bases_per_kmer as usize
+    }
+
+    pub fn codec(&self) -> BucketCodec {
+        self.codec
+    }
+
+    pub fn entry_count(&self) -> u64 {
+        self.entry_count
+    }
+
+    /// True if both headers describe the same contents; the payload length is not compared.
+    pub fn matches(&self, other: &Self) -> bool {
+        self.record_type == other.record_type
+            && self.bases_per_kmer == other.bases_per_kmer
+            && self.codec == other.codec
+            && self.parameters == other.parameters
+            && self.entry_count == other.entry_count
+    }
+
+    pub fn write<W: Write>(&self, buffer: &mut W) -> Result<()> {
+        buffer.write_all(&MAGIC)?;
+        buffer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        buffer.write_all(&[
+            self.record_type as u8,
+            self.bases_per_kmer,
+            self.codec as u8,
+        ])?;
+        buffer.write_all(&(self.parameters.len() as u32).to_le_bytes())?;
+        buffer.write_all(self.parameters.as_bytes())?;
+        buffer.write_all(&self.entry_count.to_le_bytes())?;
+        buffer.write_all(&self.payload_length.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(file_buffer: &mut R) -> Result<Self> {
+        let mut magic = [0; 4];
+        file_buffer.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(anyhow!("Not a bucket file (magic bytes {magic:?})"));
+        }
+        let mut buffer = [0; 2];
+        file_buffer.read_exact(&mut buffer)?;
+        let version = u16::from_le_bytes(buffer);
+        if version != FORMAT_VERSION {
+            return Err(anyhow!(
+                "Unsupported bucket format version {version}, expected {FORMAT_VERSION}"
+            ));
+        }
+        let mut buffer = [0; 3];
+        file_buffer.read_exact(&mut buffer)?;
+        let record_type = RecordType::from_u8(buffer[0])
+            .ok_or_else(|| anyhow!("Unknown bucket record type {}", buffer[0]))?;
+        let bases_per_kmer = buffer[1];
+        let codec = BucketCodec::from_u8(buffer[2])
+            .ok_or_else(|| anyhow!("Unknown bucket codec {}", buffer[2]))?;
+        let mut buffer = [0; 4];
+        file_buffer.read_exact(&mut buffer)?;
+        let mut parameters = vec![0; u32::from_le_bytes(buffer) as usize];
+        file_buffer.read_exact(&mut parameters)?;
+        let parameters = String::from_utf8(parameters)
+            .map_err(|_| anyhow!("Bucket header parameters are not valid UTF-8"))?;
+        let mut buffer = [0; 8];
+        file_buffer.read_exact(&mut buffer)?;
+        let entry_count = u64::from_le_bytes(buffer);
+        file_buffer.read_exact(&mut buffer)?;
+        let payload_length = u64::from_le_bytes(buffer);
+        Ok(Self {
+            record_type,
+            bases_per_kmer,
+            codec,
+            parameters,
+            entry_count,
+            payload_length,
+        })
+    }
+
+    /// Writes a complete bucket file: header, payload and checksum.
+    /// `write_payload` writes the records, which are compressed according to the codec;
+    /// the header is updated with the payload length afterwards.
+    pub fn write_file<F>(&self, file: File, write_payload: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+    {
+        let mut header = self.clone();
+        let mut file = io::BufWriter::new(file);
+        header.write(&mut file)?;
+        let mut payload = ChecksumWriter::new(file);
+        self.codec.compress(&mut payload, write_payload)?;
+        let (mut file, payload_length, checksum) = payload.finish();
+        file.write_all(&checksum.to_le_bytes())?;
+        header.payload_length = payload_length;
+        file.seek(SeekFrom::Start(0))?; // Flushes the buffer
+        header.write(&mut file)?;
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Opens a bucket file, and checks header and length.
+    /// Returns the header, and a reader of the payload that verifies the checksum once it
+    /// reaches the end of the payload.
+    pub fn open_file<P: AsRef<Path>>(
+        filename: P,
+        record_type: RecordType,
+    ) -> Result<(Self, PayloadReader)> {
+        let file = File::open(filename.as_ref())?;
+        let filename = filename.as_ref().display().to_string();
+        let file_length = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+        let header = Self::read(&mut file)
+            .map_err(|e| anyhow!("Invalid bucket header in {filename}: {e}"))?;
+        if header.record_type != record_type {
+            return Err(anyhow!(
+                "Bucket file {filename} contains {:?} records, expected {record_type:?}",
+                header.record_type
+            ));
+        }
+        let payload_start = file.stream_position()?;
+        let expected_length = payload_start + header.payload_length + CHECKSUM_SIZE;
+        if file_length != expected_length {
+            return Err(anyhow!(
+                "Bucket file {filename} is {file_length} bytes long, expected {expected_length}; truncated or corrupt"
+            ));
+        }
+        let payload = PayloadReader::new(file.take(header.payload_length), filename);
+        Ok((header, payload))
+    }
+}
+
+/// Counts and checksums everything written through it.
+pub struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+    length: u64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+            length: 0,
+        }
+    }
+
+    /// Returns the inner writer, the number of bytes written, and their checksum.
+    pub fn finish(self) -> (W, u64, u32) {
+        (self.inner, self.length, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.length += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the payload of a bucket file, and checksums it on the way.
+/// Once all of the payload has been read, the checksum is compared to the stored one;
+/// a mismatch is returned as an `InvalidData` error.
+#[derive(Debug)]
+pub struct PayloadReader {
+    inner: Take<BufReader<File>>,
+    hasher: Hasher,
+    filename: String,
+    verified: bool,
+}
+
+impl PayloadReader {
+    fn new(inner: Take<BufReader<File>>, filename: String) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+            filename,
+            verified: false,
+        }
+    }
+
+    fn verify(&mut self) -> io::Result<()> {
+        let mut buffer = [0; CHECKSUM_SIZE as usize];
+        self.inner.get_mut().read_exact(&mut buffer)?;
+        let stored_checksum = u32::from_le_bytes(buffer);
+        let checksum = self.hasher.clone().finalize();
+        self.verified = true;
+        if checksum != stored_checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Checksum mismatch in bucket file {}: stored {stored_checksum:08x}, computed {checksum:08x}",
+                    self.filename
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Read for PayloadReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        if self.inner.limit() == 0 && !self.verified {
+            self.verify()?;
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_open_file() {
+        let filename = std::env::temp_dir().join("read_grouper_test_write_and_open_file.pairs");
+        let header =
+            BucketHeader::new(21, BucketCodec::Raw, "test").for_file(RecordType::KmerRead, 3);
+        header
+            .write_file(File::create(&filename).unwrap(), |buffer| {
+                buffer.write_all(&[1, 2, 3])?;
+                Ok(())
+            })
+            .unwrap();
+
+        let (read_header, mut file) =
+            BucketHeader::open_file(&filename, RecordType::KmerRead).unwrap();
+        assert!(read_header.matches(&header));
+        assert_eq!(read_header.bases_per_kmer(), 21);
+        let mut payload = [0; 3];
+        file.read_exact(&mut payload).unwrap();
+        assert_eq!(payload, [1, 2, 3]);
+        assert!(BucketHeader::open_file(&filename, RecordType::ReadPairKmer).is_err());
+
+        // Corrupt the payload
+        let mut bytes = std::fs::read(&filename).unwrap();
+        let payload_start = bytes.len() - 7;
+        bytes[payload_start] = 9;
+        std::fs::write(&filename, &bytes).unwrap();
+        let (_, mut file) = BucketHeader::open_file(&filename, RecordType::KmerRead).unwrap();
+        let e = file.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("Checksum mismatch"));
+
+        // Truncate the file
+        std::fs::write(&filename, &bytes[..bytes.len() - 1]).unwrap();
+        let e = BucketHeader::open_file(&filename, RecordType::KmerRead).unwrap_err();
+        assert!(e.to_string().contains("truncated"));
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
@@ -1,30 +1,143 @@
-use crate::ReadId;
+use crate::{read_grouper_config::ReadGrouperConfig, read_stats::ReadStats, ReadId};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
 
-#[derive(Default, Debug)]
+/// The bucket files of one sample, by shard.
+/// Can be saved as a TOML manifest, to pass the buckets from one pipeline stage to the next.
+#[derive(Default, Debug, Serialize, Deserialize)]
 pub struct BucketList {
-    filenames: Vec<String>,
-    number_of_reads: ReadId,
     sample_name: String,
+    number_of_reads: ReadId,
+    bases_per_kmer: usize,
+    parameters: String,
+    read_index: Option<String>,
+    #[serde(default)]
+    mate_links: Option<String>,
+    shards: Vec<Vec<String>>,
+    read_stats: ReadStats,
+    #[serde(default)]
+    config: ReadGrouperConfig,
 }
 
 impl BucketList {
-    pub fn new(sample_name: String, filenames: Vec<String>, number_of_reads: ReadId) -> Self {
+    pub fn new(
+        sample_name: String,
+        shards: Vec<Vec<String>>,
+        number_of_reads: ReadId,
+        bases_per_kmer: usize,
+        parameters: String,
+    ) -> Self {
         Self {
-            filenames,
+            shards,
             number_of_reads,
+            bases_per_kmer,
             sample_name,
+            read_stats: ReadStats::default(),
+            config: ReadGrouperConfig::default(),
+            parameters,
+            read_index: None,
+            mate_links: None,
         }
     }
 
-    pub fn filenames(&self) -> &Vec<String> {
-        &self.filenames
+    pub fn read_manifest(filename: &str) -> Result<Self> {
+        let manifest = fs::read_to_string(filename)
+            .map_err(|e| anyhow!("Could not read manifest {filename}: {e}"))?;
+        toml::from_str(&manifest).map_err(|e| anyhow!("Invalid manifest {filename}: {e}"))
+    }
+
+    pub fn write_manifest(&self, filename: &str) -> Result<()> {
+        fs::write(filename, toml::to_string(self)?)
+            .map_err(|e| anyhow!("Could not write manifest {filename}: {e}"))
+    }
+
+    pub fn set_read_stats(&mut self, read_stats: ReadStats) {
+        self.read_stats = read_stats;
+    }
+
+    /// The bucket files of each shard.
+    pub fn shards(&self) -> &[Vec<String>] {
+        &self.shards
+    }
+
+    /// The file mapping `ReadId`s to read names; see `ReadIndex`.
+    pub fn read_index(&self) -> Option<&str> {
+        self.read_index.as_deref()
+    }
+
+    pub fn set_read_index(&mut self, read_index: String) {
+        self.read_index = Some(read_index);
+    }
+
+    /// The mate links file of `MateMode::Linked`; see `write_mate_links`.
+    pub fn mate_links(&self) -> Option<&str> {
+        self.mate_links.as_deref()
+    }
+
+    pub fn set_mate_links(&mut self, mate_links: String) {
+        self.mate_links = Some(mate_links);
+    }
+
+    /// The settings of the `ReadGrouper` that wrote the buckets.
+    pub fn config(&self) -> &ReadGrouperConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: ReadGrouperConfig) {
+        self.config = config;
+    }
+
+    /// The bucket files of all shards.
+    pub fn filenames(&self) -> Vec<String> {
+        self.shards.iter().flatten().cloned().collect()
     }
 
     pub fn number_of_reads(&self) -> ReadId {
         self.number_of_reads
     }
 
+    pub fn bases_per_kmer(&self) -> usize {
+        self.bases_per_kmer
+    }
+
     pub fn sample_name(&self) -> &str {
         &self.sample_name
     }
+
+    pub fn read_stats(&self) -> &ReadStats {
+        &self.read_stats
+    }
+
+    /// Describes the input and settings the buckets were created with.
+    pub fn parameters(&self) -> &str {
+        &self.parameters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let filename = std::env::temp_dir().join("read_grouper_test_manifest.toml");
+        let filename = filename.to_str().unwrap();
+        let shards = vec![vec!["a_shard0_1_0.pairs".to_string()], vec![]];
+        let mut bucket_list = BucketList::new("a".to_string(), shards, 7, 21, "k=21".to_string());
+        let mut read_stats = ReadStats::default();
+        read_stats.add_record();
+        bucket_list.set_read_stats(read_stats);
+        bucket_list.write_manifest(filename).unwrap();
+
+        let read = BucketList::read_manifest(filename).unwrap();
+        assert_eq!(read.sample_name(), "a");
+        assert_eq!(read.number_of_reads(), 7);
+        assert_eq!(read.bases_per_kmer(), 21);
+        assert_eq!(read.parameters(), "k=21");
+        assert_eq!(read.shards(), bucket_list.shards());
+        assert_eq!(read.read_stats().records(), 1);
+        assert_eq!(read.config(), bucket_list.config());
+        std::fs::remove_file(filename).unwrap();
+    }
 }
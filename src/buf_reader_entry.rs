@@ -1,41 +1,79 @@
-use crate::data_bucket::BucketDataRead;
+use crate::{bucket_header::BucketHeader, data_bucket::BucketDataRead};
 use anyhow::{anyhow, Result};
 use std::cmp::Ordering;
-use std::{fs::File, io::BufReader};
+use std::io::Read;
 
 pub struct BufReaderEntry<T> {
-    buffer: BufReader<File>,
+    filename: String,
+    buffer: Box<dyn Read + Send>,
+    is_delta: bool,
     last_entry_read: T,
+    entries_left: u64,
+    header: BucketHeader,
 }
 
 impl<T: std::cmp::Ord + BucketDataRead + Default> BufReaderEntry<T> {
     pub fn new(filename: &str) -> Result<Self> {
-        let file = File::open(filename)?;
+        let (header, payload) = BucketHeader::open_file(filename, T::RECORD_TYPE)?;
+        let buffer = header.codec().decompress(payload)?;
         let mut ret = Self {
-            buffer: BufReader::new(file),
+            filename: filename.to_string(),
+            buffer,
+            is_delta: header.codec().is_delta(),
             last_entry_read: T::default(),
+            entries_left: header.entry_count(),
+            header,
         };
 
-        match ret.last_entry_read.read(&mut ret.buffer) {
-            Ok(()) => {}
-            Err(_e) => return Err(anyhow!("No entries found in buffer {filename}")),
-        };
+        if !ret.read_next_entry()? {
+            return Err(anyhow!("No entries found in buffer {filename}"));
+        }
 
         Ok(ret)
     }
 
+    pub fn bases_per_kmer(&self) -> usize {
+        self.header.bases_per_kmer()
+    }
+
+    pub fn header(&self) -> &BucketHeader {
+        &self.header
+    }
+
     #[inline(always)]
     pub fn last_entry_read(&self) -> &T {
         &self.last_entry_read
     }
 
-    // Returns true if the file has reached the end
+    /// Reads the next entry. Returns false once all entries in the header have been read.
+    /// A payload that ends early, or has data left after the last entry, is an error.
     #[inline(always)]
-    pub fn read_next_entry_failed(&mut self) -> bool {
-        match self.last_entry_read.read(&mut self.buffer) {
-            Ok(()) => false,
-            Err(_) => true,
+    pub fn read_next_entry(&mut self) -> Result<bool> {
+        if self.entries_left == 0 {
+            let mut byte = [0; 1];
+            return match self.buffer.read(&mut byte) {
+                Ok(0) => Ok(false),
+                Ok(_) => Err(anyhow!(
+                    "Bucket file {} has data after the last entry",
+                    self.filename
+                )),
+                Err(e) => Err(anyhow!("Could not read bucket file {}: {e}", self.filename)),
+            };
         }
+        self.entries_left -= 1;
+        let result = if self.is_delta {
+            self.last_entry_read.read_delta(&mut self.buffer)
+        } else {
+            self.last_entry_read.read(&mut self.buffer)
+        };
+        result.map_err(|e| {
+            anyhow!(
+                "Bucket file {} is truncated or corrupt, with {} entries left: {e}",
+                self.filename,
+                self.entries_left + 1
+            )
+        })?;
+        Ok(true)
     }
 }
 
@@ -1,41 +1,109 @@
-use anyhow::Result;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use crate::bucket_header::{BucketHeader, RecordType};
+use anyhow::{anyhow, Error, Result};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::mem;
 use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use std::sync::{Arc, Mutex};
-use std::{mem, thread};
+use std::thread::{self, JoinHandle};
 
-pub trait BucketDataWrite {
-    fn write(&self, buffer: &mut BufWriter<File>) -> Result<()>;
+pub const DEFAULT_WRITER_THREADS: usize = 2;
+pub const DEFAULT_WRITER_QUEUE_DEPTH: usize = 2;
+
+/// A record that can be stored in bucket files.
+pub trait BucketRecord {
+    const RECORD_TYPE: RecordType;
 }
 
-pub trait BucketDataRead {
-    fn read(&mut self, file_buffer: &mut BufReader<File>) -> Result<()>;
+pub trait BucketDataWrite: BucketRecord {
+    fn write<W: Write>(&self, buffer: &mut W) -> Result<()>;
+
+    /// Writes the difference to the previous record, as varints.
+    fn write_delta<W: Write>(&self, previous: &Self, buffer: &mut W) -> Result<()>;
+}
+
+pub trait BucketDataRead: BucketRecord {
+    fn read<R: Read>(&mut self, file_buffer: &mut R) -> Result<()>;
+
+    /// Reads a record written by `write_delta`; `self` holds the previous record.
+    fn read_delta<R: Read>(&mut self, file_buffer: &mut R) -> Result<()>;
 }
 
+/// A full bucket waiting to be written: bucket id and data.
+type PendingBucket<T> = (usize, Vec<T>);
+
+/// Collects data in memory, and writes it to sorted bucket files on disk.
+/// Full buckets are handed to a fixed pool of writer threads via a bounded queue;
+/// `add` blocks while the queue is full, so at most
+/// `bucket_size * (writer_threads + queue_depth + 1)` entries are held in memory.
+/// Each `DataBucket` has its own pool, so a `ShardedBucket` of 16 shards starts 32
+/// writer threads by default; they mostly wait for the disk.
+/// `header` describes the run settings; it is stored in each file, and an existing
+/// file is only reused if its header matches.
 #[derive(Debug)]
 pub struct DataBucket<T> {
     pairs: Vec<T>,
     bucket_id: usize,
     bucket_size: usize,
-    bucket_dir: String,
-    sample_name: String,
-    ending: String,
-    currently_writing: Arc<Mutex<usize>>,
-    filenames: Arc<Mutex<Vec<String>>>,
+    bucket_files: Arc<BucketFiles>,
+    sender: Option<SyncSender<PendingBucket<T>>>,
+    writers: Vec<JoinHandle<()>>,
 }
 
-impl<T: std::cmp::Ord + BucketDataWrite + Send + 'static> DataBucket<T> {
-    pub fn new(bucket_size: usize, bucket_dir: &str, sample_name: &str, ending: &str) -> Self {
-        Self {
-            bucket_id: 0,
+impl<T: std::cmp::Ord + BucketDataWrite + Default + Send + 'static> DataBucket<T> {
+    pub fn new(
+        bucket_size: usize,
+        bucket_dir: &str,
+        sample_name: &str,
+        ending: &str,
+        header: &BucketHeader,
+    ) -> Self {
+        Self::with_writer_pool(
+            bucket_size,
+            bucket_dir,
+            sample_name,
+            ending,
+            header,
+            DEFAULT_WRITER_THREADS,
+            DEFAULT_WRITER_QUEUE_DEPTH,
+        )
+    }
+
+    pub fn with_writer_pool(
+        bucket_size: usize,
+        bucket_dir: &str,
+        sample_name: &str,
+        ending: &str,
+        header: &BucketHeader,
+        writer_threads: usize,
+        queue_depth: usize,
+    ) -> Self {
+        let bucket_files = Arc::new(BucketFiles {
             bucket_size,
             bucket_dir: bucket_dir.to_string(),
             sample_name: sample_name.to_string(),
             ending: ending.to_string(),
+            header: header.clone(),
+            filenames: Mutex::new(Vec::new()),
+            errors: Mutex::new(Vec::new()),
+        });
+        let (sender, receiver) = sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let writers = (0..writer_threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                let bucket_files = bucket_files.clone();
+                thread::spawn(move || bucket_files.write_pending(&receiver))
+            })
+            .collect();
+        Self {
+            bucket_id: 0,
+            bucket_size,
+            bucket_files,
             pairs: Vec::with_capacity(bucket_size + 1),
-            currently_writing: Arc::new(Mutex::new(0)),
-            filenames: Arc::new(Mutex::new(Vec::new())),
+            sender: Some(sender),
+            writers,
         }
     }
 
@@ -45,11 +113,28 @@ impl<T: std::cmp::Ord + BucketDataWrite + Send + 'static> DataBucket<T> {
         self.flush();
     }
 
+    /// Writes the remaining data, and waits for all writer threads.
+    /// Fails if writing any of the buckets failed.
     pub fn finish(&mut self) -> Result<Vec<String>> {
-        self.start_writing();
-        self.write_to_disk()?; // Blocking main thread for final write
-        self.wait_for_zero_lock();
-        let filenames = self.filenames.lock().unwrap().clone();
+        let pairs = mem::take(&mut self.pairs);
+        self.send(pairs);
+        self.sender = None; // Closing the queue ends the writer threads once it is empty
+        let mut errors = mem::take(&mut *self.bucket_files.errors.lock().unwrap());
+        for writer in self.writers.drain(..) {
+            if writer.join().is_err() {
+                errors.push(anyhow!("A bucket writer thread panicked"));
+            }
+        }
+        errors.append(&mut self.bucket_files.errors.lock().unwrap());
+        if !errors.is_empty() {
+            let messages = errors.iter().map(|e| format!("{e:#}")).collect::<Vec<_>>();
+            return Err(anyhow!(
+                "Writing {} bucket(s) failed: {}",
+                errors.len(),
+                messages.join("; ")
+            ));
+        }
+        let filenames = self.bucket_files.filenames.lock().unwrap().clone();
         Ok(filenames)
     }
 
@@ -58,90 +143,263 @@ impl<T: std::cmp::Ord + BucketDataWrite + Send + 'static> DataBucket<T> {
         if !self.is_full() {
             return;
         }
-
-        // Duplicate this bucket, with empty data
-        let mut bucket_to_write = Self {
-            bucket_id: self.bucket_id,
-            bucket_size: self.bucket_size,
-            bucket_dir: self.bucket_dir.clone(),
-            sample_name: self.sample_name.clone(),
-            ending: self.ending.clone(),
-            pairs: Vec::with_capacity(self.bucket_size + 1),
-            currently_writing: self.currently_writing.clone(),
-            filenames: self.filenames.clone(),
-        };
-
-        // Swap data with the new bucket
-        mem::swap(&mut bucket_to_write.pairs, &mut self.pairs);
-
-        // Increase the bucket counter
-        self.bucket_id += 1;
-
-        // Start writing the bucket to disk in a new thread
-        bucket_to_write.start_writing();
-        let _ = thread::spawn(move || bucket_to_write.write_to_disk().unwrap());
+        let pairs = mem::replace(&mut self.pairs, Vec::with_capacity(self.bucket_size + 1));
+        self.send(pairs);
     }
 
-    fn wait_for_zero_lock(&self) {
-        // Wait for all writing threads to finish
-        loop {
-            if *self.currently_writing.lock().unwrap() == 0 {
-                break;
+    /// Queues the data for writing, blocking while the queue is full.
+    fn send(&mut self, pairs: Vec<T>) {
+        let bucket_id = self.bucket_id;
+        self.bucket_id += 1;
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => {
+                let e = anyhow!("Bucket {bucket_id} was added after finishing");
+                self.bucket_files.errors.lock().unwrap().push(e);
+                return;
             }
-            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        if sender.send((bucket_id, pairs)).is_err() {
+            let e = anyhow!("No bucket writer thread left to write bucket {bucket_id}");
+            self.bucket_files.errors.lock().unwrap().push(e);
         }
     }
 
     #[inline(always)]
-    fn start_writing(&self) {
-        *self.currently_writing.lock().unwrap() += 1;
+    fn is_full(&self) -> bool {
+        self.pairs.len() >= self.bucket_size
     }
+}
 
-    #[inline(always)]
-    fn end_writing(&self) {
-        *self.currently_writing.lock().unwrap() -= 1;
-    }
+/// Naming and bookkeeping for the files of a `DataBucket`, shared with the writer threads.
+#[derive(Debug)]
+struct BucketFiles {
+    bucket_size: usize,
+    bucket_dir: String,
+    sample_name: String,
+    ending: String,
+    header: BucketHeader,
+    filenames: Mutex<Vec<String>>,
+    errors: Mutex<Vec<Error>>,
+}
 
-    #[inline(always)]
-    fn is_full(&self) -> bool {
-        self.pairs.len() >= self.bucket_size
+impl BucketFiles {
+    /// Writer thread loop: writes queued buckets until the queue is closed.
+    fn write_pending<T: std::cmp::Ord + BucketDataWrite + Default>(
+        &self,
+        receiver: &Mutex<Receiver<PendingBucket<T>>>,
+    ) {
+        loop {
+            // Only hold the lock while waiting, not while writing
+            let pending = receiver.lock().unwrap().recv();
+            let (bucket_id, mut pairs) = match pending {
+                Ok(pending) => pending,
+                Err(_) => break, // Queue closed and empty
+            };
+            if let Err(e) = self.write_to_disk(bucket_id, &mut pairs) {
+                self.errors.lock().unwrap().push(e);
+            }
+        }
     }
 
-    fn set_filename(&self, filename: String) {
+    fn set_filename(&self, bucket_id: usize, filename: String) {
         // Add filename to list at bucket_id position
         let mut filenames = self.filenames.lock().unwrap();
-        while filenames.len() <= self.bucket_id {
+        while filenames.len() <= bucket_id {
             filenames.push(String::new());
         }
-        *filenames.get_mut(self.bucket_id).unwrap() = filename;
+        *filenames.get_mut(bucket_id).unwrap() = filename;
     }
 
-    fn construct_filename(&self) -> String {
+    fn construct_filename(&self, bucket_id: usize) -> String {
         format!(
             "{}/{}_{}_{}.{}",
-            self.bucket_dir, self.sample_name, self.bucket_size, self.bucket_id, self.ending
+            self.bucket_dir, self.sample_name, self.bucket_size, bucket_id, self.ending
         )
     }
 
-    fn write_to_disk(&mut self) -> Result<()> {
-        if self.pairs.is_empty() {
-            self.end_writing();
+    /// Writes the sorted bucket to a temporary file, which is renamed once complete, or
+    /// removed on failure.
+    /// An existing file is reused if its length is intact and its header matches this run;
+    /// its checksum is verified when it is read.
+    fn write_to_disk<T: std::cmp::Ord + BucketDataWrite + Default>(
+        &self,
+        bucket_id: usize,
+        pairs: &mut [T],
+    ) -> Result<()> {
+        if pairs.is_empty() {
             return Ok(());
         }
-        let filename = self.construct_filename();
+        let filename = self.construct_filename(bucket_id);
+        let header = self.header.for_file(T::RECORD_TYPE, pairs.len() as u64);
         if Path::new(&filename).exists() {
-            self.set_filename(filename);
-            self.end_writing();
-            return Ok(());
-        }
-        self.pairs.sort();
-        let file = File::create(&filename)?;
-        let mut file = BufWriter::new(file);
-        for pair in &self.pairs {
-            pair.write(&mut file)?;
+            match BucketHeader::open_file(&filename, T::RECORD_TYPE) {
+                Ok((existing, _)) if existing.matches(&header) => {
+                    self.set_filename(bucket_id, filename);
+                    return Ok(());
+                }
+                _ => {} // From a different run, or corrupt; overwrite
+            }
         }
-        self.set_filename(filename);
-        self.end_writing();
+        pairs.sort();
+        let tmp_filename = format!("{filename}.tmp");
+        File::create(&tmp_filename)
+            .map_err(Error::from)
+            .and_then(|file| {
+                header.write_file(file, |mut buffer| {
+                    if header.codec().is_delta() {
+                        let first = T::default();
+                        let mut previous = &first;
+                        for pair in pairs.iter() {
+                            pair.write_delta(previous, &mut buffer)?;
+                            previous = pair;
+                        }
+                    } else {
+                        for pair in pairs.iter() {
+                            pair.write(&mut buffer)?;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .map_err(|e| anyhow!("Could not write bucket file {tmp_filename}: {e}"))
+            .and_then(|_| {
+                fs::rename(&tmp_filename, &filename)
+                    .map_err(|e| anyhow!("Could not rename {tmp_filename} to {filename}: {e}"))
+            })
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp_filename); // Do not leave partial files behind
+            })?;
+        self.set_filename(bucket_id, filename);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bucket_codec::BucketCodec, kmer::Kmer, kmer_read::KmerRead,
+        multi_buf_reader::MultiBufReader,
+    };
+
+    fn header() -> BucketHeader {
+        BucketHeader::new(16, BucketCodec::Delta, "test")
+    }
+
+    #[test]
+    fn test_writer_pool() {
+        // Buckets written out of order by several threads are listed by bucket id
+        let bucket_dir = std::env::temp_dir().join("read_grouper_test_writer_pool");
+        let _ = fs::remove_dir_all(&bucket_dir);
+        fs::create_dir_all(&bucket_dir).unwrap();
+        let bucket_dir = bucket_dir.to_str().unwrap();
+        for (writer_threads, queue_depth) in [(1, 1), (3, 1), (4, 0)] {
+            let mut bucket = DataBucket::with_writer_pool(
+                2,
+                bucket_dir,
+                &format!("test{writer_threads}"),
+                "pairs",
+                &header(),
+                writer_threads,
+                queue_depth,
+            );
+            for i in 0..21 {
+                bucket.add(KmerRead::new(Kmer::new(i), i as u32));
+            }
+            let filenames = bucket.finish().unwrap();
+            let expected = (0..11)
+                .map(|bucket_id| format!("{bucket_dir}/test{writer_threads}_2_{bucket_id}.pairs"))
+                .collect::<Vec<_>>();
+            assert_eq!(filenames, expected);
+            assert!(filenames
+                .iter()
+                .all(|filename| Path::new(filename).exists()));
+        }
+        fs::remove_dir_all(bucket_dir).unwrap();
+    }
+
+    /// Writes the records with read ids `first_id..first_id + count` to a single bucket file.
+    fn write_bucket(bucket_dir: &str, parameters: &str, first_id: u32, count: u32) -> String {
+        let header = BucketHeader::new(16, BucketCodec::Delta, parameters);
+        let mut bucket = DataBucket::new(10, bucket_dir, "test", "pairs", &header);
+        for read_id in first_id..first_id + count {
+            bucket.add(KmerRead::new(Kmer::new(read_id as u64), read_id));
+        }
+        let mut filenames = bucket.finish().unwrap();
+        assert_eq!(filenames.len(), 1);
+        filenames.pop().unwrap()
+    }
+
+    fn read_ids(filename: &str) -> Vec<u32> {
+        MultiBufReader::<KmerRead>::new(&[filename.to_string()])
+            .unwrap()
+            .map(|kmer_read| kmer_read.unwrap().read_id())
+            .collect()
+    }
+
+    #[test]
+    fn test_reuse_or_overwrite() {
+        let bucket_dir = std::env::temp_dir().join("read_grouper_test_reuse");
+        let _ = fs::remove_dir_all(&bucket_dir);
+        fs::create_dir_all(&bucket_dir).unwrap();
+        let bucket_dir = bucket_dir.to_str().unwrap();
+        let filename = write_bucket(bucket_dir, "a", 0, 5);
+        assert_eq!(read_ids(&filename), [0, 1, 2, 3, 4]);
+
+        // A file with a matching header is reused, even though the data differs
+        assert_eq!(write_bucket(bucket_dir, "a", 10, 5), filename);
+        assert_eq!(read_ids(&filename), [0, 1, 2, 3, 4]);
+
+        // Different parameters or entry counts overwrite it
+        write_bucket(bucket_dir, "b", 10, 5);
+        assert_eq!(read_ids(&filename), [10, 11, 12, 13, 14]);
+        write_bucket(bucket_dir, "b", 20, 4);
+        assert_eq!(read_ids(&filename), [20, 21, 22, 23]);
+
+        // So does a truncated file
+        let length = fs::metadata(&filename).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&filename)
+            .unwrap()
+            .set_len(length - 1)
+            .unwrap();
+        write_bucket(bucket_dir, "b", 30, 4);
+        assert_eq!(read_ids(&filename), [30, 31, 32, 33]);
+
+        // A leftover temporary file is not used
+        let tmp_filename = format!("{filename}.tmp");
+        fs::remove_file(&filename).unwrap();
+        fs::write(&tmp_filename, b"garbage").unwrap();
+        write_bucket(bucket_dir, "b", 40, 4);
+        assert_eq!(read_ids(&filename), [40, 41, 42, 43]);
+        assert!(!Path::new(&tmp_filename).exists());
+
+        // A failed write does not leave the temporary file behind
+        fs::remove_file(&filename).unwrap();
+        fs::create_dir(&filename).unwrap();
+        let header = BucketHeader::new(16, BucketCodec::Delta, "b");
+        let mut bucket = DataBucket::new(10, bucket_dir, "test", "pairs", &header);
+        bucket.add(KmerRead::new(Kmer::new(1), 1));
+        let e = bucket.finish().unwrap_err();
+        assert!(e.to_string().contains("Could not rename"));
+        assert!(!Path::new(&tmp_filename).exists());
+        fs::remove_dir_all(bucket_dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_error() {
+        // Writer threads report the error, and finish returns once they are done
+        let bucket_dir = std::env::temp_dir().join("read_grouper_test_missing_dir");
+        let _ = fs::remove_dir_all(&bucket_dir);
+        let bucket_dir = bucket_dir.to_str().unwrap();
+        let mut bucket = DataBucket::new(2, bucket_dir, "test", "pairs", &header());
+        for i in 0..9 {
+            bucket.add(KmerRead::new(Kmer::new(i), i as u32));
+        }
+        let e = bucket.finish().unwrap_err();
+        assert!(e.to_string().contains("Writing 5 bucket(s) failed"));
+        assert!(e.to_string().contains("Could not write bucket file"));
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Result};
+use flate2::read::MultiGzDecoder;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+pub const DEFAULT_PHRED_OFFSET: u8 = 33;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A single FASTQ entry. Qualities are raw Phred scores, like `bam::Record::qualities().raw()`.
+#[derive(Default, Debug)]
+pub struct FastqRecord {
+    name: Vec<u8>,
+    sequence: Vec<u8>,
+    qualities: Vec<u8>,
+}
+
+impl FastqRecord {
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    pub fn sequence(&self) -> &[u8] {
+        &self.sequence
+    }
+
+    pub fn qualities(&self) -> &[u8] {
+        &self.qualities
+    }
+}
+
+/// Reads plain or gzipped FASTQ, one record at a time.
+pub struct FastqReader<R: BufRead> {
+    reader: R,
+    phred_offset: u8,
+    line_number: usize,
+}
+
+impl FastqReader<Box<dyn BufRead>> {
+    /// Opens a FASTQ file. Gzip compression is detected from the file contents, not the name.
+    pub fn from_path<P: AsRef<Path>>(path: P, phred_offset: u8) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let is_gzipped = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+        let reader: Box<dyn BufRead> = if is_gzipped {
+            Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+        } else {
+            Box::new(reader)
+        };
+        Ok(Self::new(reader, phred_offset))
+    }
+
+    /// Returns true if the file name looks like (possibly gzipped) FASTQ.
+    pub fn is_fastq_path(file_path: &str) -> bool {
+        let file_path = file_path.strip_suffix(".gz").unwrap_or(file_path);
+        file_path.ends_with(".fastq") || file_path.ends_with(".fq")
+    }
+}
+
+impl<R: BufRead> FastqReader<R> {
+    pub fn new(reader: R, phred_offset: u8) -> Self {
+        Self {
+            reader,
+            phred_offset,
+            line_number: 0,
+        }
+    }
+
+    /// Reads the next record into `record`. Returns false at the end of the file.
+    pub fn read_into(&mut self, record: &mut FastqRecord) -> Result<bool> {
+        if !self.read_line(&mut record.name)? {
+            return Ok(false);
+        }
+        if record.name.first() != Some(&b'@') {
+            return Err(anyhow!("Expected '@' in FASTQ line {}", self.line_number));
+        }
+        record.name.remove(0);
+        if let Some(pos) = record.name.iter().position(|c| c.is_ascii_whitespace()) {
+            record.name.truncate(pos); // Drop the comment
+        }
+
+        if !self.read_line(&mut record.sequence)? {
+            return Err(anyhow!(
+                "Truncated FASTQ record at line {}",
+                self.line_number
+            ));
+        }
+        record.sequence.make_ascii_uppercase();
+
+        // The separator line is read into the quality buffer, which is overwritten next
+        if !self.read_line(&mut record.qualities)? || record.qualities.first() != Some(&b'+') {
+            return Err(anyhow!("Expected '+' in FASTQ line {}", self.line_number));
+        }
+
+        if !self.read_line(&mut record.qualities)? {
+            return Err(anyhow!(
+                "Truncated FASTQ record at line {}",
+                self.line_number
+            ));
+        }
+        if record.qualities.len() != record.sequence.len() {
+            return Err(anyhow!(
+                "Sequence and quality lengths differ in FASTQ line {}",
+                self.line_number
+            ));
+        }
+        for quality in record.qualities.iter_mut() {
+            *quality = quality.checked_sub(self.phred_offset).ok_or_else(|| {
+                anyhow!(
+                    "Quality below Phred offset {} in FASTQ line {}",
+                    self.phred_offset,
+                    self.line_number
+                )
+            })?;
+        }
+        Ok(true)
+    }
+
+    fn read_line(&mut self, line: &mut Vec<u8>) -> Result<bool> {
+        line.clear();
+        if self.reader.read_until(b'\n', line)? == 0 {
+            return Ok(false);
+        }
+        self.line_number += 1;
+        while line.last().is_some_and(|c| *c == b'\n' || *c == b'\r') {
+            line.pop();
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    const FASTQ: &[u8] = b"@read1 comment\nACGTN\n+\nIIII#\n@read2\nacgt\n+read2\n5555\n";
+
+    #[test]
+    fn test_read_into() {
+        let mut reader = FastqReader::new(FASTQ, DEFAULT_PHRED_OFFSET);
+        let mut record = FastqRecord::default();
+        assert!(reader.read_into(&mut record).unwrap());
+        assert_eq!(record.name, b"read1");
+        assert_eq!(record.sequence(), b"ACGTN");
+        assert_eq!(record.qualities(), [40, 40, 40, 40, 2]);
+        assert!(reader.read_into(&mut record).unwrap());
+        assert_eq!(record.name, b"read2");
+        assert_eq!(record.sequence(), b"ACGT");
+        assert_eq!(record.qualities(), [20, 20, 20, 20]);
+        assert!(!reader.read_into(&mut record).unwrap());
+
+        let mut reader = FastqReader::new(FASTQ, 64);
+        assert!(reader.read_into(&mut record).is_err());
+    }
+
+    #[test]
+    fn test_from_path_gzipped() {
+        let filename = std::env::temp_dir().join("read_grouper_test_from_path_gzipped.fastq.gz");
+        let mut encoder = GzEncoder::new(File::create(&filename).unwrap(), Compression::fast());
+        encoder.write_all(FASTQ).unwrap();
+        encoder.finish().unwrap();
+
+        let mut reader = FastqReader::from_path(&filename, DEFAULT_PHRED_OFFSET).unwrap();
+        let mut record = FastqRecord::default();
+        let mut names = Vec::new();
+        while reader.read_into(&mut record).unwrap() {
+            names.push(record.name.clone());
+        }
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(names, [b"read1".to_vec(), b"read2".to_vec()]);
+    }
+}
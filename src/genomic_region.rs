@@ -0,0 +1,286 @@
+use anyhow::{anyhow, Result};
+use bam::{bam_reader::RegionViewer, Region};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{BufRead, BufReader, Read, Seek},
+    str::FromStr,
+};
+
+/// A part of a reference sequence, by name; 0-based, half-open.
+/// Without an end, the region extends to the end of the reference.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenomicRegion {
+    reference: String,
+    start: u32,
+    end: Option<u32>,
+}
+
+impl GenomicRegion {
+    pub fn new(reference: &str, start: u32, end: Option<u32>) -> Self {
+        Self {
+            reference: reference.to_string(),
+            start,
+            end,
+        }
+    }
+
+    /// Reads the regions of a BED file; only the first three columns are used.
+    pub fn from_bed_file(filename: &str) -> Result<Vec<Self>> {
+        let file =
+            File::open(filename).map_err(|e| anyhow!("Could not open BED file {filename}: {e}"))?;
+        let mut ret = Vec::new();
+        for (line_number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            let mut parts = line.split('\t');
+            let region = match (parts.next(), parts.next(), parts.next()) {
+                (Some(reference), Some(start), Some(end)) => {
+                    match (start.trim().parse(), end.trim().parse()) {
+                        (Ok(start), Ok(end)) => Some(Self::new(reference, start, Some(end))),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            ret.push(
+                region
+                    .ok_or_else(|| anyhow!("{filename}:{}: invalid BED line", line_number + 1))?,
+            );
+        }
+        Ok(ret)
+    }
+
+    pub fn reference(&self) -> &str {
+        &self.reference
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn end(&self) -> Option<u32> {
+        self.end
+    }
+
+    /// Parses the `start-end` or `start` part of a region string, 1-based and inclusive.
+    fn parse_range(range: &str) -> Option<(u32, Option<u32>)> {
+        let range = range.replace(',', "");
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.parse::<u32>().ok()?, Some(end.parse::<u32>().ok()?)),
+            None => (range.parse::<u32>().ok()?, None),
+        };
+        (start > 0 && end.is_none_or(|end| end >= start)).then_some((start - 1, end))
+    }
+}
+
+/// Parses `ref`, `ref:start` or `ref:start-end` (1-based, inclusive, as in samtools),
+/// e.g. `chr6:29,941,260-29,945,884`. As reference names may contain `:` (e.g. HLA alleles),
+/// a suffix that is not a valid range is taken as part of the name, and so is a bare start
+/// position after a name that already contains `:`.
+impl FromStr for GenomicRegion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(anyhow!("Empty region"));
+        }
+        match s.rsplit_once(':') {
+            Some((reference, range)) if !reference.is_empty() => match Self::parse_range(range) {
+                Some((_, None)) if reference.contains(':') => Ok(Self::new(s, 0, None)),
+                Some((start, end)) => Ok(Self::new(reference, start, end)),
+                None => Ok(Self::new(s, 0, None)),
+            },
+            _ => Ok(Self::new(s, 0, None)),
+        }
+    }
+}
+
+impl fmt::Display for GenomicRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{}:{}-{end}", self.reference, self.start + 1),
+            None => write!(f, "{}:{}", self.reference, self.start + 1),
+        }
+    }
+}
+
+/// Resolves regions against the references of a BAM header.
+/// The result is sorted, with overlapping and adjacent regions merged, and ends clamped
+/// to the reference lengths.
+pub fn resolve_regions(regions: &[GenomicRegion], header: &bam::Header) -> Result<Vec<Region>> {
+    let mut resolved = Vec::with_capacity(regions.len());
+    for region in regions {
+        let ref_id = header.reference_id(region.reference()).ok_or_else(|| {
+            anyhow!(
+                "Unknown reference '{}' in region {region}",
+                region.reference()
+            )
+        })?;
+        let length = header.reference_len(ref_id).unwrap_or(0);
+        let end = region.end().unwrap_or(length).min(length);
+        if region.start() < end {
+            resolved.push((ref_id, region.start(), end));
+        }
+    }
+    resolved.sort_unstable();
+
+    let mut ret: Vec<Region> = Vec::with_capacity(resolved.len());
+    for (ref_id, start, end) in resolved {
+        match ret.last_mut() {
+            Some(last) if last.ref_id() == ref_id && last.end() >= start => {
+                last.set_end(last.end().max(end));
+            }
+            _ => ret.push(Region::new(ref_id, start, end)),
+        }
+    }
+    Ok(ret)
+}
+
+/// Opens a BAM file with its BAI index at `{bam_path}.bai`. An index older than the BAM
+/// file may be stale, which is reported as a warning rather than an error, like samtools does.
+pub fn open_indexed_bam(bam_path: &str) -> Result<bam::IndexedReader<File>> {
+    let bai_path = format!("{bam_path}.bai");
+    Ok(bam::IndexedReader::build()
+        .additional_threads(4)
+        .modification_time(bam::bam_reader::ModificationTime::warn(move |warning| {
+            eprintln!("Warning: {bai_path}: {warning}")
+        }))
+        .from_path(bam_path)?)
+}
+
+/// Whether the BAI index is older than the BAM file, so possibly stale.
+pub fn is_index_older(bam_path: &str, bai_path: &str) -> bool {
+    let modified = |path: &str| fs::metadata(path).and_then(|metadata| metadata.modified());
+    match (modified(bam_path), modified(bai_path)) {
+        (Ok(bam_time), Ok(bai_time)) => bai_time < bam_time,
+        _ => false,
+    }
+}
+
+/// Fetches the records overlapping `regions[index]`, from regions as returned by
+/// `resolve_regions`. Records starting before the end of the previous region on the same
+/// reference also overlap that region, so they were returned already, and are skipped.
+pub fn fetch_region<'a, R: Read + Seek>(
+    reader: &'a mut bam::IndexedReader<R>,
+    regions: &[Region],
+    index: usize,
+) -> Result<RegionViewer<'a, R>> {
+    let region = &regions[index];
+    let previous_end = match index.checked_sub(1).map(|previous| &regions[previous]) {
+        Some(previous) if previous.ref_id() == region.ref_id() => previous.end() as i32,
+        _ => 0,
+    };
+    Ok(reader.fetch_by(region, move |record| record.start() >= previous_end)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_resolve() {
+        let region = "chr6:29,941,260-29,945,884"
+            .parse::<GenomicRegion>()
+            .unwrap();
+        assert_eq!(region, GenomicRegion::new("chr6", 29941259, Some(29945884)));
+        assert_eq!(region.to_string(), "chr6:29941260-29945884");
+        assert_eq!(
+            "chr1".parse::<GenomicRegion>().unwrap(),
+            GenomicRegion::new("chr1", 0, None)
+        );
+        assert_eq!(
+            "chr1:100".parse::<GenomicRegion>().unwrap(),
+            GenomicRegion::new("chr1", 99, None)
+        );
+        assert_eq!(
+            "HLA-A*01:01:01:01".parse::<GenomicRegion>().unwrap(),
+            GenomicRegion::new("HLA-A*01:01:01:01", 0, None)
+        );
+        assert_eq!(
+            "HLA-A*01:01:01:01:100-200"
+                .parse::<GenomicRegion>()
+                .unwrap(),
+            GenomicRegion::new("HLA-A*01:01:01:01", 99, Some(200))
+        );
+        assert!("".parse::<GenomicRegion>().is_err());
+
+        let mut header = bam::Header::new();
+        header.push_line("@SQ\tSN:chr1\tLN:1000").unwrap();
+        header.push_line("@SQ\tSN:chr2\tLN:500").unwrap();
+        let regions = [
+            GenomicRegion::new("chr2", 100, Some(200)),
+            GenomicRegion::new("chr1", 500, Some(600)),
+            GenomicRegion::new("chr1", 0, Some(100)),
+            GenomicRegion::new("chr1", 550, Some(2000)),
+            GenomicRegion::new("chr1", 100, Some(200)),
+        ];
+        let resolved = resolve_regions(&regions, &header)
+            .unwrap()
+            .iter()
+            .map(|region| (region.ref_id(), region.start(), region.end()))
+            .collect::<Vec<_>>();
+        assert_eq!(resolved, [(0, 0, 200), (0, 500, 1000), (1, 100, 200)]);
+        assert!(resolve_regions(&[GenomicRegion::new("chrX", 0, None)], &header).is_err());
+    }
+
+    #[test]
+    fn test_fetch_region() {
+        let bam_path = std::env::temp_dir().join("read_grouper_test_fetch_region.bam");
+        let bam_path = bam_path.to_str().unwrap();
+        // (name, 1-based position, length)
+        let reads = [
+            ("before", 50, 10),
+            ("first", 201, 4),
+            ("spanning", 290, 30), // Overlaps both regions
+            ("gap", 305, 4),
+            ("second", 401, 4),
+            ("after", 600, 4),
+        ];
+        let sam_lines = reads
+            .iter()
+            .map(|(name, position, length)| {
+                format!(
+                    "{name}\t0\tchr1\t{position}\t60\t{length}M\t*\t0\t0\t{}\t{}",
+                    "A".repeat(*length),
+                    "I".repeat(*length)
+                )
+            })
+            .collect::<Vec<_>>();
+        let sam_lines = sam_lines.iter().map(String::as_str).collect::<Vec<_>>();
+        crate::test_bam::write_indexed_bam(bam_path, &sam_lines);
+
+        let mut reader = bam::IndexedReader::build()
+            .modification_time(bam::bam_reader::ModificationTime::Ignore)
+            .from_path(bam_path)
+            .unwrap();
+        let regions = resolve_regions(
+            &[
+                GenomicRegion::new("chr1", 310, Some(500)),
+                GenomicRegion::new("chr1", 100, Some(300)),
+            ],
+            reader.header(),
+        )
+        .unwrap();
+        assert_eq!(regions.len(), 2);
+        let mut names = Vec::new();
+        let mut record = bam::Record::new();
+        for index in 0..regions.len() {
+            let mut viewer = fetch_region(&mut reader, &regions, index).unwrap();
+            while bam::RecordReader::read_into(&mut viewer, &mut record).unwrap() {
+                names.push(String::from_utf8(record.name().to_vec()).unwrap());
+            }
+        }
+        assert_eq!(names, ["first", "spanning", "second"]);
+        std::fs::remove_file(bam_path).unwrap();
+        std::fs::remove_file(format!("{bam_path}.bai")).unwrap();
+    }
+}
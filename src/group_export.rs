@@ -0,0 +1,659 @@
+use crate::{
+    bucket_list::BucketList,
+    genomic_region::{fetch_region, open_indexed_bam, resolve_regions, GenomicRegion},
+    read_grouper::NON_PRIMARY_FLAGS,
+    read_groups::ReadGroups,
+    read_id_assigner::{MateMode, ReadIdAssigner},
+    ReadId,
+};
+use anyhow::{anyhow, Result};
+use bam::{
+    header::{EntryType, HeaderEntry, HeaderLine},
+    RecordReader, RecordWriter,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    str::FromStr,
+};
+
+pub const DEFAULT_MIN_GROUP_SIZE: usize = 2;
+pub const DEFAULT_MAX_OPEN_FILES: usize = 256;
+const PROGRAM_NAME: &str = "read_grouper";
+
+/// Default tag for the group id of a record in a tagged BAM.
+pub const DEFAULT_GROUP_TAG: [u8; 2] = *b"XG";
+/// Default tag for the group size of a record in a tagged BAM.
+pub const DEFAULT_GROUP_SIZE_TAG: [u8; 2] = *b"XZ";
+/// As the group tag, writes the group id as read group, with an `@RG` line per group.
+const READ_GROUP_TAG: [u8; 2] = *b"RG";
+/// The read group of records in groups smaller than the minimum group size.
+const UNASSIGNED_READ_GROUP: &str = "unassigned";
+
+/// How the records of each read group are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupExport {
+    /// One BAM file per group, `{prefix}{group_id}.bam`.
+    BamPerGroup,
+    /// One FASTQ file per group, `{prefix}{group_id}.fastq`.
+    /// Reverse-strand records are reverse-complemented back to their sequenced orientation.
+    FastqPerGroup,
+    /// The input BAM as a single file `{prefix}.bam`, with the group id and group size
+    /// of each record as integer tags, and a `@PG` header line.
+    /// With `RG` as group tag, the group id is written as `RG:Z` string instead, and the
+    /// `@RG` lines of the input are replaced by one `@RG ID:<group id>` line per group of at
+    /// least the minimum group size. Records of smaller groups get read group `unassigned`.
+    /// The new `@RG` lines keep the other fields (`SM`, `LB`, ...) of the first input `@RG`.
+    TaggedBam {
+        group_tag: [u8; 2],
+        size_tag: [u8; 2],
+    },
+}
+
+impl GroupExport {
+    fn parse_tag(tag: &str) -> Result<[u8; 2]> {
+        match tag.as_bytes() {
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphanumeric() => Ok([*a, *b]),
+            _ => Err(anyhow!("Invalid tag '{tag}'")),
+        }
+    }
+}
+
+/// Parses `bam`, `fastq` or `tagged_bam[:<group tag>[,<size tag>]]`, e.g. `tagged_bam:XG,XZ`.
+impl FromStr for GroupExport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, tags) = match s.split_once(':') {
+            Some((name, tags)) => (name, Some(tags)),
+            None => (s, None),
+        };
+        match (name, tags) {
+            ("bam", None) => Ok(Self::BamPerGroup),
+            ("fastq", None) => Ok(Self::FastqPerGroup),
+            ("tagged_bam", tags) => {
+                let (group_tag, size_tag) = match tags {
+                    None => (DEFAULT_GROUP_TAG, DEFAULT_GROUP_SIZE_TAG),
+                    Some(tags) => match tags.split_once(',') {
+                        Some((group_tag, size_tag)) => {
+                            (Self::parse_tag(group_tag)?, Self::parse_tag(size_tag)?)
+                        }
+                        None => (Self::parse_tag(tags)?, DEFAULT_GROUP_SIZE_TAG),
+                    },
+                };
+                if group_tag == size_tag {
+                    return Err(anyhow!("Group id and group size need different tags"));
+                }
+                if size_tag == READ_GROUP_TAG {
+                    return Err(anyhow!("RG can only be the group id tag"));
+                }
+                Ok(Self::TaggedBam {
+                    group_tag,
+                    size_tag,
+                })
+            }
+            _ => Err(anyhow!("Unknown group export '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for GroupExport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BamPerGroup => write!(f, "bam"),
+            Self::FastqPerGroup => write!(f, "fastq"),
+            Self::TaggedBam {
+                group_tag,
+                size_tag,
+            } => write!(
+                f,
+                "tagged_bam:{},{}",
+                String::from_utf8_lossy(group_tag),
+                String::from_utf8_lossy(size_tag)
+            ),
+        }
+    }
+}
+
+/// Writes the records of a BAM file by read group.
+/// The BAM file is re-read with the same `ReadId` numbering as in `ReadGrouper::read_bam_file`,
+/// using the `MateMode` and regions of the manifest the groups were made from; so it must be
+/// the same file.
+#[derive(Debug)]
+pub struct GroupExporter<'a> {
+    read_groups: &'a ReadGroups,
+    mate_mode: MateMode,
+    min_group_size: usize,
+    max_open_files: usize,
+    command_line: Option<String>,
+    regions: Vec<GenomicRegion>,
+}
+
+impl<'a> GroupExporter<'a> {
+    /// `bucket_list` is the manifest of any stage of the run that made `read_groups`.
+    pub fn new(read_groups: &'a ReadGroups, bucket_list: &BucketList) -> Result<Self> {
+        if read_groups.number_of_reads() != bucket_list.number_of_reads() as usize {
+            return Err(anyhow!(
+                "The read groups have {} reads, but the manifest has {}",
+                read_groups.number_of_reads(),
+                bucket_list.number_of_reads()
+            ));
+        }
+        Ok(Self {
+            read_groups,
+            mate_mode: bucket_list.config().mate_mode,
+            min_group_size: DEFAULT_MIN_GROUP_SIZE,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            command_line: None,
+            regions: bucket_list.config().regions.clone(),
+        })
+    }
+
+    /// Sets the minimum number of reads of a group to get its own file.
+    pub fn set_min_group_size(&mut self, min_group_size: usize) {
+        self.min_group_size = min_group_size;
+    }
+
+    /// Sets the maximum number of per-group files written at once.
+    /// More groups are written in several passes over the input.
+    pub fn set_max_open_files(&mut self, max_open_files: usize) -> Result<()> {
+        if max_open_files == 0 {
+            return Err(anyhow!("Maximum number of open files must be at least 1"));
+        }
+        self.max_open_files = max_open_files;
+        Ok(())
+    }
+
+    /// Sets the command line recorded in the `@PG` header line of a tagged BAM.
+    pub fn set_command_line(&mut self, command_line: &str) {
+        self.command_line = Some(command_line.to_string());
+    }
+
+    /// Exports the records of `bam_path`; returns the names of the files written.
+    pub fn export(
+        &self,
+        bam_path: &str,
+        export: &GroupExport,
+        output_prefix: &str,
+    ) -> Result<Vec<String>> {
+        match export {
+            GroupExport::TaggedBam {
+                group_tag,
+                size_tag,
+            } => {
+                let filename = format!("{output_prefix}.bam");
+                self.export_tagged_bam(bam_path, group_tag, size_tag, &filename)?;
+                Ok(vec![filename])
+            }
+            GroupExport::BamPerGroup | GroupExport::FastqPerGroup => {
+                let group_ids = (0..self.read_groups.number_of_groups() as ReadId)
+                    .filter(|group_id| self.is_assigned(*group_id))
+                    .collect::<Vec<_>>();
+                let mut filenames = Vec::with_capacity(group_ids.len());
+                for batch in group_ids.chunks(self.max_open_files) {
+                    filenames.append(&mut self.export_per_group(
+                        bam_path,
+                        export,
+                        batch,
+                        output_prefix,
+                    )?);
+                }
+                Ok(filenames)
+            }
+        }
+    }
+
+    fn export_tagged_bam(
+        &self,
+        bam_path: &str,
+        group_tag: &[u8; 2],
+        size_tag: &[u8; 2],
+        filename: &str,
+    ) -> Result<()> {
+        let mut header = Self::read_header(bam_path)?;
+        let read_group_tag = *group_tag == READ_GROUP_TAG;
+        if read_group_tag {
+            header = self.with_group_read_groups(&header)?;
+        }
+        header
+            .push_entry(self.program_entry(&header))
+            .map_err(|e| anyhow!(e))?;
+        let mut writer = bam::BamWriter::from_path(filename, header)?;
+        self.for_each_record(bam_path, |group_id, record| {
+            let group_size = self.read_groups.group_size(group_id);
+            let tags = record.tags_mut();
+            tags.remove(group_tag);
+            tags.remove(size_tag);
+            if read_group_tag {
+                let read_group = if self.is_assigned(group_id) {
+                    group_id.to_string()
+                } else {
+                    UNASSIGNED_READ_GROUP.to_string()
+                };
+                tags.push_string(group_tag, read_group.as_bytes());
+            } else {
+                tags.push_num(group_tag, group_id);
+            }
+            tags.push_num(size_tag, group_size);
+            writer.write(record)?;
+            Ok(())
+        })?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Whether the group has at least the minimum group size.
+    fn is_assigned(&self, group_id: ReadId) -> bool {
+        self.read_groups.group_size(group_id) as usize >= self.min_group_size
+    }
+
+    /// A copy of `header` with its `@RG` lines replaced by one per group of at least the
+    /// minimum size, and one for the other groups, before the `@PG` lines.
+    fn with_group_read_groups(&self, header: &bam::Header) -> Result<bam::Header> {
+        let is_type = |line: &HeaderLine, entry_type| matches!(line, HeaderLine::Entry(entry) if entry.entry_type() == entry_type);
+        let mut ret = bam::Header::new();
+        for line in header.lines() {
+            match line {
+                line if is_type(line, EntryType::ReadGroup)
+                    || is_type(line, EntryType::Program) => {}
+                HeaderLine::Entry(entry) => {
+                    ret.push_entry(entry.clone()).map_err(|e| anyhow!(e))?
+                }
+                HeaderLine::Comment(comment) => ret.push_comment(comment.clone()),
+            }
+        }
+        let template = header.lines().find_map(|line| match line {
+            HeaderLine::Entry(entry) if entry.entry_type() == EntryType::ReadGroup => Some(entry),
+            _ => None,
+        });
+        let read_group = |id: String| match template {
+            Some(template) => {
+                let mut entry = template.clone();
+                entry.insert(b"ID", id);
+                entry
+            }
+            None => HeaderEntry::read_group(id),
+        };
+        let mut unassigned = false;
+        for group_id in 0..self.read_groups.number_of_groups() as ReadId {
+            if self.is_assigned(group_id) {
+                ret.push_entry(read_group(group_id.to_string()))
+                    .map_err(|e| anyhow!(e))?;
+            } else {
+                unassigned = true;
+            }
+        }
+        if unassigned {
+            ret.push_entry(read_group(UNASSIGNED_READ_GROUP.to_string()))
+                .map_err(|e| anyhow!(e))?;
+        }
+        for line in header.lines() {
+            if let HeaderLine::Entry(entry) = line {
+                if entry.entry_type() == EntryType::Program {
+                    ret.push_entry(entry.clone()).map_err(|e| anyhow!(e))?;
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// The `@PG` line for a tagged BAM, chained to the last program already in `header`.
+    fn program_entry(&self, header: &bam::Header) -> HeaderEntry {
+        let program_ids = header
+            .lines()
+            .filter_map(|line| match line {
+                HeaderLine::Entry(entry) if entry.entry_type() == EntryType::Program => {
+                    entry.get(b"ID")
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let id = (0..)
+            .map(|n| match n {
+                0 => PROGRAM_NAME.to_string(),
+                n => format!("{PROGRAM_NAME}.{n}"),
+            })
+            .find(|id| !program_ids.contains(&id.as_str()))
+            .unwrap_or_default();
+        let mut entry = HeaderEntry::program(id);
+        entry.push(b"PN", PROGRAM_NAME.to_string());
+        if let Some(previous_id) = program_ids.last() {
+            entry.push(b"PP", previous_id.to_string());
+        }
+        entry.push(b"VN", env!("CARGO_PKG_VERSION").to_string());
+        if let Some(command_line) = &self.command_line {
+            entry.push(b"CL", command_line.to_string());
+        }
+        entry
+    }
+
+    /// One pass over the input, writing the groups in `batch`.
+    fn export_per_group(
+        &self,
+        bam_path: &str,
+        export: &GroupExport,
+        batch: &[ReadId],
+        output_prefix: &str,
+    ) -> Result<Vec<String>> {
+        let header = Self::read_header(bam_path)?;
+        let mut writers = HashMap::with_capacity(batch.len());
+        let mut filenames = Vec::with_capacity(batch.len());
+        for group_id in batch {
+            let writer = match export {
+                GroupExport::FastqPerGroup => {
+                    let filename = format!("{output_prefix}{group_id}.fastq");
+                    let writer = GroupWriter::Fastq(BufWriter::new(File::create(&filename)?));
+                    filenames.push(filename);
+                    writer
+                }
+                _ => {
+                    let filename = format!("{output_prefix}{group_id}.bam");
+                    let writer = GroupWriter::Bam(Box::new(bam::BamWriter::from_path(
+                        &filename,
+                        header.clone(),
+                    )?));
+                    filenames.push(filename);
+                    writer
+                }
+            };
+            writers.insert(*group_id, writer);
+        }
+
+        self.for_each_record(bam_path, |group_id, record| {
+            if let Some(writer) = writers.get_mut(&group_id) {
+                writer.write(record)?;
+            }
+            Ok(())
+        })?;
+        for (_, writer) in writers.drain() {
+            writer.finish()?;
+        }
+        Ok(filenames)
+    }
+
+    fn read_header(bam_path: &str) -> Result<bam::Header> {
+        Ok(bam::BamReader::from_path(bam_path, 0)?.header().clone())
+    }
+
+    /// Calls `on_record` with the group id of every record, in `ReadGrouper::read_bam_file` order.
+    /// Fails unless the records get exactly the `ReadId`s of the read groups.
+    fn for_each_record<F>(&self, bam_path: &str, mut on_record: F) -> Result<()>
+    where
+        F: FnMut(ReadId, &mut bam::Record) -> Result<()>,
+    {
+        let mut read_ids = ReadIdAssigner::new(self.mate_mode);
+        if self.regions.is_empty() {
+            let mut reader = bam::BamReader::from_path(bam_path, 4)?;
+            self.read_records(&mut reader, &mut read_ids, &mut on_record)?;
+        } else {
+            let mut reader = open_indexed_bam(bam_path)?;
+            let regions = resolve_regions(&self.regions, reader.header())?;
+            for index in 0..regions.len() {
+                let mut viewer = fetch_region(&mut reader, &regions, index)?;
+                self.read_records(&mut viewer, &mut read_ids, &mut on_record)?;
+            }
+        }
+        if read_ids.number_of_ids() as usize != self.read_groups.number_of_reads() {
+            return Err(anyhow!(
+                "{bam_path} has {} reads, but the read groups have {}; the input does not match the read groups",
+                read_ids.number_of_ids(),
+                self.read_groups.number_of_reads()
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_records<R, F>(
+        &self,
+        reader: &mut R,
+        read_ids: &mut ReadIdAssigner,
+        on_record: &mut F,
+    ) -> Result<()>
+    where
+        R: RecordReader,
+        F: FnMut(ReadId, &mut bam::Record) -> Result<()>,
+    {
+        let mut record = bam::Record::new();
+        while reader.read_into(&mut record)? {
+            let primary = record.flag().0 & NON_PRIMARY_FLAGS == 0;
+            let read_id = read_ids.assign(record.name(), primary);
+            let group_id = self.read_groups.group_id(read_id).ok_or_else(|| {
+                anyhow!("Read {read_id} has no group; the input does not match the read groups")
+            })?;
+            on_record(group_id, &mut record)?;
+        }
+        Ok(())
+    }
+}
+
+enum GroupWriter {
+    Bam(Box<bam::BamWriter<File>>),
+    Fastq(BufWriter<File>),
+}
+
+impl GroupWriter {
+    fn write(&mut self, record: &bam::Record) -> Result<()> {
+        match self {
+            Self::Bam(writer) => writer.write(record)?,
+            Self::Fastq(writer) => write_fastq(writer, record)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Bam(mut writer) => writer.finish()?,
+            Self::Fastq(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Writes a record as FASTQ, in its sequenced orientation.
+/// Secondary and supplementary alignments are skipped, so every read is written once.
+fn write_fastq<W: Write>(writer: &mut W, record: &bam::Record) -> Result<()> {
+    if record.flag().0 & NON_PRIMARY_FLAGS != 0 {
+        return Ok(());
+    }
+    let mut sequence = record.sequence().to_vec();
+    let mut qualities = match record.qualities().available() {
+        true => record.qualities().to_readable(),
+        false => vec![b'!'; sequence.len()],
+    };
+    if record.flag().is_reverse_strand() {
+        sequence.reverse();
+        sequence
+            .iter_mut()
+            .for_each(|base| *base = complement(*base));
+        qualities.reverse();
+    }
+    writer.write_all(b"@")?;
+    writer.write_all(record.name())?;
+    writer.write_all(b"\n")?;
+    writer.write_all(&sequence)?;
+    writer.write_all(b"\n+\n")?;
+    writer.write_all(&qualities)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+#[inline(always)]
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "bam".parse::<GroupExport>().unwrap(),
+            GroupExport::BamPerGroup
+        );
+        assert_eq!(
+            "fastq".parse::<GroupExport>().unwrap(),
+            GroupExport::FastqPerGroup
+        );
+        let tagged = "tagged_bam:xg,xs".parse::<GroupExport>().unwrap();
+        assert_eq!(
+            tagged,
+            GroupExport::TaggedBam {
+                group_tag: *b"xg",
+                size_tag: *b"xs"
+            }
+        );
+        assert_eq!(tagged.to_string(), "tagged_bam:xg,xs");
+        assert_eq!(
+            "tagged_bam".parse::<GroupExport>().unwrap().to_string(),
+            "tagged_bam:XG,XZ"
+        );
+        assert_eq!(
+            "tagged_bam:YG".parse::<GroupExport>().unwrap().to_string(),
+            "tagged_bam:YG,XZ"
+        );
+        assert_eq!(
+            "tagged_bam:RG".parse::<GroupExport>().unwrap().to_string(),
+            "tagged_bam:RG,XZ"
+        );
+        assert!("tagged_bam:XG,RG".parse::<GroupExport>().is_err());
+        assert!("tagged_bam:XGX".parse::<GroupExport>().is_err());
+        assert!("tagged_bam:XG,XG".parse::<GroupExport>().is_err());
+        assert!("sam".parse::<GroupExport>().is_err());
+    }
+
+    #[test]
+    fn test_program_entry() {
+        let read_groups = ReadGroups::default();
+        let mut exporter = GroupExporter::new(&read_groups, &BucketList::default()).unwrap();
+        exporter.set_command_line("read_grouper export");
+        let mut header = bam::Header::new();
+        header.push_line("@PG\tID:bwa\tPN:bwa").unwrap();
+        header.push_line("@PG\tID:read_grouper\tPP:bwa").unwrap();
+        let entry = exporter.program_entry(&header);
+        assert_eq!(entry.get(b"ID"), Some("read_grouper.1"));
+        assert_eq!(entry.get(b"PP"), Some("read_grouper"));
+        assert_eq!(entry.get(b"CL"), Some("read_grouper export"));
+    }
+
+    #[test]
+    fn test_write_fastq() {
+        let mut fastq = Vec::new();
+        for sam_line in [
+            "fwd\t0\tchr1\t100\t60\t4M\t*\t0\t0\tACGG\tABCD",
+            "fwd\t256\tchr1\t500\t0\t4M\t*\t0\t0\tACGG\tABCD",
+            "fwd\t2048\tchr1\t900\t60\t4M\t*\t0\t0\tACGG\tABCD",
+            "rev\t16\tchr1\t200\t60\t4M\t*\t0\t0\tACGG\tABCD",
+            "noqual\t4\t*\t0\t0\t*\t*\t0\t0\tAC\t*",
+        ] {
+            write_fastq(&mut fastq, &crate::test_bam::record(sam_line)).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(fastq).unwrap(),
+            "@fwd\nACGG\n+\nABCD\n@rev\nCCGT\n+\nDCBA\n@noqual\nAC\n+\n!!\n"
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = std::env::temp_dir().join("read_grouper_test_export");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap();
+        let bam_path = format!("{dir}/sample.bam");
+        crate::test_bam::write_indexed_bam(
+            &bam_path,
+            &[
+                "r0\t0\tchr1\t100\t60\t4M\t*\t0\t0\tACGT\tIIII\tRG:Z:lane1",
+                "r0\t256\tchr1\t150\t0\t4M\t*\t0\t0\tACGT\tIIII\tRG:Z:lane1",
+                "r1\t0\tchr1\t200\t60\t4M\t*\t0\t0\tCCGT\tIIII\tRG:Z:lane1",
+                "r2\t0\tchr1\t300\t60\t4M\t*\t0\t0\tGCGT\tIIII\tRG:Z:lane1",
+            ],
+        );
+        let mut union_find = crate::union_find::UnionFind::new(4);
+        union_find.union(0, 1).unwrap();
+        union_find.union(0, 2).unwrap();
+        let read_groups = ReadGroups::from_union_find(union_find);
+        let bucket_list = BucketList::new("sample".to_string(), Vec::new(), 4, 16, String::new());
+        let exporter = GroupExporter::new(&read_groups, &bucket_list).unwrap();
+
+        // Secondary records are left out of FASTQ
+        let prefix = format!("{dir}/group");
+        let filenames = exporter
+            .export(&bam_path, &GroupExport::FastqPerGroup, &prefix)
+            .unwrap();
+        assert_eq!(filenames, [format!("{prefix}0.fastq")]);
+        let fastq = std::fs::read_to_string(&filenames[0]).unwrap();
+        assert_eq!(
+            fastq.lines().filter(|line| line.starts_with('@')).count(),
+            2
+        );
+
+        let export = "tagged_bam:RG".parse::<GroupExport>().unwrap();
+        let filenames = exporter.export(&bam_path, &export, &prefix).unwrap();
+        let reader = bam::BamReader::from_path(&filenames[0], 0).unwrap();
+        let read_groups = reader
+            .header()
+            .lines()
+            .filter_map(|line| match line {
+                HeaderLine::Entry(entry) if entry.entry_type() == EntryType::ReadGroup => Some((
+                    entry.get(b"ID").unwrap().to_string(),
+                    entry.get(b"SM").map(str::to_string),
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Only groups of the minimum size get their own read group, with the input fields
+        assert_eq!(
+            read_groups,
+            [
+                ("0".to_string(), Some("sample".to_string())),
+                ("unassigned".to_string(), Some("sample".to_string()))
+            ]
+        );
+        let tags = reader
+            .map(|record| {
+                let record = record.unwrap();
+                let group_id = match record.tags().get(b"RG") {
+                    Some(bam::record::tags::TagValue::String(value, _)) => value.to_vec(),
+                    _ => Vec::new(),
+                };
+                let group_size = match record.tags().get(b"XZ") {
+                    Some(bam::record::tags::TagValue::Int(value, _)) => value,
+                    _ => 0,
+                };
+                (String::from_utf8(group_id).unwrap(), group_size)
+            })
+            .collect::<Vec<_>>();
+        let expected = [("0", 3), ("0", 3), ("0", 3), ("unassigned", 1)];
+        assert_eq!(tags, expected.map(|(id, size)| (id.to_string(), size)));
+
+        // The numbering must match the read groups
+        let bucket_list = BucketList::new("sample".to_string(), Vec::new(), 5, 16, String::new());
+        assert!(GroupExporter::new(&read_groups_of(4), &bucket_list).is_err());
+        let five_reads = read_groups_of(5);
+        let exporter = GroupExporter::new(&five_reads, &bucket_list).unwrap();
+        let e = exporter
+            .export(&bam_path, &"tagged_bam".parse().unwrap(), &prefix)
+            .unwrap_err();
+        assert!(e.to_string().contains("does not match"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn read_groups_of(number_of_reads: ReadId) -> ReadGroups {
+        ReadGroups::from_union_find(crate::union_find::UnionFind::new(number_of_reads))
+    }
+}
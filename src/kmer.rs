@@ -1,13 +1,58 @@
+use anyhow::{anyhow, Result};
 use rayon::prelude::*;
-use std::{cmp::Ordering, fmt};
+use std::{cmp::Ordering, fmt, str::FromStr};
 
-use crate::KmerBits;
+use crate::{kmer_sampling::KmerSampling, KmerBits};
 
-const BASES_PER_KMER: usize = 16;
+pub const DEFAULT_BASES_PER_KMER: usize = 16;
+pub const MAX_BASES_PER_KMER: usize = KmerBits::BITS as usize / 2;
+
+/// What happens to the rest of a read after a low-quality or non-ACGT base.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BadBasePolicy {
+    /// Skip the kmers covering the bad base, and continue after it.
+    #[default]
+    SkipWindow,
+    /// Keep the kmers before the bad base, and ignore the rest of the read.
+    AbandonRead,
+}
+
+impl FromStr for BadBasePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip_window" => Ok(Self::SkipWindow),
+            "abandon_read" => Ok(Self::AbandonRead),
+            _ => Err(anyhow!("Unknown bad base policy '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for BadBasePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SkipWindow => write!(f, "skip_window"),
+            Self::AbandonRead => write!(f, "abandon_read"),
+        }
+    }
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct Kmer(KmerBits);
 
+/// Invertible 64-bit mix (MurmurHash3 finalizer); similar inputs give unrelated outputs.
+#[inline(always)]
+pub fn mix_hash(value: KmerBits) -> u64 {
+    let mut h = value;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
+
 impl Kmer {
     #[inline(always)]
     pub fn new(kmer: KmerBits) -> Self {
@@ -15,36 +60,59 @@ impl Kmer {
     }
 
     #[inline(always)]
-    pub fn to_le_bytes(&self) -> [u8; 4] {
+    pub fn bits(&self) -> KmerBits {
+        self.0
+    }
+
+    #[inline(always)]
+    pub fn to_le_bytes(&self) -> [u8; 8] {
         self.0.to_le_bytes()
     }
 
+    /// The shard of this kmer, out of `shards`.
+    /// Uses the mixed hash of the kmer, so that skewed kmer distributions, like the many
+    /// kmers starting with A, still spread evenly over the shards.
     #[inline(always)]
-    fn reverse_by_two_bit_groups_u32(value: KmerBits) -> KmerBits {
-        // println!("Original : {value:032b}");
-        let mut value = ((value & 0x33333333) << 2) | ((value & 0xCCCCCCCC) >> 2); // swap adjacent pairs
+    pub fn shard(&self, shards: usize) -> usize {
+        (mix_hash(self.0) % shards as u64) as usize
+    }
 
-        // println!("Bit pairs: {value:032b}");
-        value = ((value & 0x0F0F0F0F) << 4) | ((value & 0xF0F0F0F0) >> 4); // swap nibbles
+    #[inline(always)]
+    fn reverse_by_two_bit_groups_u64(value: KmerBits) -> KmerBits {
+        // println!("Original : {value:064b}");
+        let mut value = ((value & 0x3333333333333333) << 2) | ((value & 0xCCCCCCCCCCCCCCCC) >> 2); // swap adjacent pairs
 
-        // println!("Nibbles  : {value:032b}");
-        value = ((value & 0x00FF00FF) << 8) | ((value & 0xFF00FF00) >> 8); // swap bytes
+        // println!("Bit pairs: {value:064b}");
+        value = ((value & 0x0F0F0F0F0F0F0F0F) << 4) | ((value & 0xF0F0F0F0F0F0F0F0) >> 4); // swap nibbles
 
-        // println!("Bytes    : {value:032b}");
-        value = ((value & 0x0000FFFF) << 16) | ((value & 0xFFFF0000) >> 16); // sawp words
+        // println!("Nibbles  : {value:064b}");
+        value = value.swap_bytes(); // reverse bytes
 
-        // println!("Words    : {value:032b}");
+        // println!("Bytes    : {value:064b}");
         value
     }
 
+    /// Reverse complement of a kmer with `bases_per_kmer` bases in the lowest bits.
+    #[inline(always)]
+    fn reverse_complement(kmer: KmerBits, bases_per_kmer: usize) -> KmerBits {
+        Self::reverse_by_two_bit_groups_u64(!kmer) >> (KmerBits::BITS as usize - 2 * bases_per_kmer)
+    }
+
+    /// Bit mask covering the lowest `bases_per_kmer` bases.
+    #[inline(always)]
+    fn kmer_mask(bases_per_kmer: usize) -> KmerBits {
+        KmerBits::MAX >> (KmerBits::BITS as usize - 2 * bases_per_kmer)
+    }
+
     #[inline(always)]
     fn _build_kmer_min(
         seq: &[u8],
         quality_scores: &[u8],
         min_base_quality: u8,
+        bases_per_kmer: usize,
     ) -> Option<KmerBits> {
         let mut kmer = 0;
-        for i in 0..BASES_PER_KMER {
+        for i in 0..bases_per_kmer {
             let base = seq[i];
             if quality_scores[i] < min_base_quality {
                 return None;
@@ -58,7 +126,7 @@ impl Kmer {
             };
             kmer = (kmer << 2) | base_forward;
         }
-        let reverse_kmer = Self::reverse_by_two_bit_groups_u32(!kmer); // Reverse complement
+        let reverse_kmer = Self::reverse_complement(kmer, bases_per_kmer);
         Some(kmer.min(reverse_kmer))
     }
 
@@ -66,14 +134,15 @@ impl Kmer {
         sequence: &[u8],
         qualities: &[u8],
         min_base_quality: u8,
+        bases_per_kmer: usize,
     ) -> Vec<KmerBits> {
-        let range = 0..(sequence.len() - BASES_PER_KMER);
+        let range = 0..sequence.len().saturating_sub(bases_per_kmer);
         let mut ret = range
             .into_par_iter()
             .filter_map(|start| {
-                let seq = &sequence[start..start + BASES_PER_KMER];
-                let qual = &qualities[start..start + BASES_PER_KMER];
-                Self::_build_kmer_min(seq, qual, min_base_quality)
+                let seq = &sequence[start..start + bases_per_kmer];
+                let qual = &qualities[start..start + bases_per_kmer];
+                Self::_build_kmer_min(seq, qual, min_base_quality, bases_per_kmer)
             })
             .collect::<Vec<_>>();
         ret.sort();
@@ -81,14 +150,16 @@ impl Kmer {
         ret
     }
 
-    #[inline(always)]
+    /// Builds a kmer and its reverse complement from the first `bases_per_kmer` bases.
+    #[cfg(test)]
     fn build_kmer_pair(
         sequence_bases: &[u8],
         quality_scores: &[u8],
         min_base_quality: u8,
+        bases_per_kmer: usize,
     ) -> Option<(KmerBits, KmerBits)> {
         let mut kmer = 0;
-        for i in 0..BASES_PER_KMER {
+        for i in 0..bases_per_kmer {
             if quality_scores[i] < min_base_quality {
                 return None;
             }
@@ -101,54 +172,89 @@ impl Kmer {
             };
             kmer = (kmer << 2) | base_forward;
         }
-        let reverse_kmer = Self::reverse_by_two_bit_groups_u32(!kmer); // Reverse complement
+        let reverse_kmer = Self::reverse_complement(kmer, bases_per_kmer);
         Some((kmer, reverse_kmer))
     }
 
+    /// Returns the sorted, unique canonical kmers of a read.
+    /// A base below `min_base_quality`, or one that is not ACGT, invalidates all kmers covering it;
+    /// depending on `bad_base_policy`, kmer building restarts after it or stops for the rest of the read.
     #[inline(always)]
     pub fn kmers_from_record_incremental(
         sequence: &[u8],
         quality_scores: &[u8],
         min_base_quality: u8,
+        bases_per_kmer: usize,
+        sampling: &KmerSampling,
+        bad_base_policy: BadBasePolicy,
     ) -> Vec<KmerBits> {
-        let mut ret = Vec::with_capacity(sequence.len() - BASES_PER_KMER + 1);
-
-        // Generate first kmer
-        let seq = &sequence[0..BASES_PER_KMER];
-        let qual = &quality_scores[0..BASES_PER_KMER];
-        let (mut kmer, mut reverse_complement_kmer) =
-            match Self::build_kmer_pair(seq, qual, min_base_quality) {
-                Some(x) => x,
-                None => return ret,
-            };
-        ret.push(kmer.min(reverse_complement_kmer));
+        let mut ret = Vec::with_capacity((sequence.len() + 1).saturating_sub(bases_per_kmer));
+        let mask = Self::kmer_mask(bases_per_kmer);
 
-        for i in BASES_PER_KMER..(sequence.len()) {
-            let base = sequence[i];
-            if quality_scores[i] < min_base_quality {
-                break; // Bad quality, abandon entire read
-            }
-            let base_forward: KmerBits = match base {
-                b'A' => 0,
-                b'C' => 1,
-                b'G' => 2,
-                b'T' => 3,
-                _ => break, // Weird IUPAC letter, abandon entire read
+        // Kmers of the current stretch of good bases, in sequence order
+        let mut stretch = Vec::with_capacity(ret.capacity());
+        let mut kmer = 0;
+        let mut good_bases = 0;
+        for (base, quality) in sequence.iter().zip(quality_scores) {
+            let base_forward: Option<KmerBits> = match base {
+                _ if *quality < min_base_quality => None, // Bad quality
+                b'A' => Some(0),
+                b'C' => Some(1),
+                b'G' => Some(2),
+                b'T' => Some(3),
+                _ => None, // Weird IUPAC letter
             };
-            kmer = (kmer << 2) | base_forward;
-            reverse_complement_kmer = Self::reverse_by_two_bit_groups_u32(!kmer); // Reverse complement
-            ret.push(kmer.min(reverse_complement_kmer));
+            match base_forward {
+                Some(base_forward) => {
+                    kmer = ((kmer << 2) | base_forward) & mask;
+                    good_bases += 1;
+                    if good_bases >= bases_per_kmer {
+                        let reverse_complement_kmer =
+                            Self::reverse_complement(kmer, bases_per_kmer);
+                        stretch.push(kmer.min(reverse_complement_kmer));
+                    }
+                }
+                None => {
+                    Self::add_sampled_kmers(&mut ret, &mut stretch, bases_per_kmer, sampling);
+                    if bad_base_policy == BadBasePolicy::AbandonRead {
+                        break;
+                    }
+                    good_bases = 0;
+                }
+            }
         }
+        Self::add_sampled_kmers(&mut ret, &mut stretch, bases_per_kmer, sampling);
         ret.sort();
         ret.dedup();
         ret
     }
+
+    #[inline(always)]
+    fn add_sampled_kmers(
+        ret: &mut Vec<KmerBits>,
+        stretch: &mut Vec<KmerBits>,
+        bases_per_kmer: usize,
+        sampling: &KmerSampling,
+    ) {
+        if *sampling == KmerSampling::All {
+            ret.append(stretch);
+        } else {
+            ret.append(&mut sampling.sample(stretch, bases_per_kmer));
+            stretch.clear();
+        }
+    }
 }
 
+/// The number of bases is taken from the precision, e.g. `format!("{kmer:.31}")`.
+/// Without a precision, `DEFAULT_BASES_PER_KMER` bases are printed.
 impl fmt::Display for Kmer {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        for pos in 0..BASES_PER_KMER {
-            let base = match (self.0 >> (2 * (BASES_PER_KMER - pos - 1))) & 0b11 {
+        let bases_per_kmer = fmt
+            .precision()
+            .unwrap_or(DEFAULT_BASES_PER_KMER)
+            .min(MAX_BASES_PER_KMER);
+        for pos in 0..bases_per_kmer {
+            let base = match (self.0 >> (2 * (bases_per_kmer - pos - 1))) & 0b11 {
                 0 => b'A',
                 1 => b'C',
                 2 => b'G',
@@ -189,37 +295,54 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_reverse_by_two_bit_groups_u32() {
-        assert_eq!(Kmer::reverse_by_two_bit_groups_u32(3 << 30), 3);
-        assert_eq!(Kmer::reverse_by_two_bit_groups_u32(3), 3 << 30);
-        assert_eq!(Kmer::reverse_by_two_bit_groups_u32(3 << 2), 3 << 28);
-        assert_eq!(Kmer::reverse_by_two_bit_groups_u32(3 << 18), 3 << 12);
-        assert_eq!(Kmer::reverse_by_two_bit_groups_u32(3 << 16), 3 << 14);
-        assert_eq!(Kmer::reverse_by_two_bit_groups_u32(3 << 14), 3 << 16);
-        assert_eq!(Kmer::reverse_by_two_bit_groups_u32(3 << 12), 3 << 18);
-        let value: KmerBits = 12345 | 6789 << 8 | 65432 << 16 | 23456 << 24;
-        let reverse = Kmer::reverse_by_two_bit_groups_u32(value);
-        let original = Kmer::reverse_by_two_bit_groups_u32(reverse);
+    fn test_reverse_by_two_bit_groups_u64() {
+        assert_eq!(Kmer::reverse_by_two_bit_groups_u64(3 << 62), 3);
+        assert_eq!(Kmer::reverse_by_two_bit_groups_u64(3), 3 << 62);
+        assert_eq!(Kmer::reverse_by_two_bit_groups_u64(3 << 2), 3 << 60);
+        assert_eq!(Kmer::reverse_by_two_bit_groups_u64(3 << 34), 3 << 28);
+        assert_eq!(Kmer::reverse_by_two_bit_groups_u64(3 << 32), 3 << 30);
+        assert_eq!(Kmer::reverse_by_two_bit_groups_u64(3 << 30), 3 << 32);
+        assert_eq!(Kmer::reverse_by_two_bit_groups_u64(3 << 28), 3 << 34);
+        let value: KmerBits = 12345 | 6789 << 8 | 65432 << 16 | 23456 << 24 | 98765 << 40;
+        let reverse = Kmer::reverse_by_two_bit_groups_u64(value);
+        let original = Kmer::reverse_by_two_bit_groups_u64(reverse);
         assert_eq!(value, original);
     }
 
+    #[test]
+    fn test_reverse_complement() {
+        let kmer = Kmer::build_kmer_pair(b"AACGT", &[40; 5], 40, 5).unwrap().0;
+        let reverse = Kmer::build_kmer_pair(b"ACGTT", &[40; 5], 40, 5).unwrap().0;
+        assert_eq!(Kmer::reverse_complement(kmer, 5), reverse);
+        assert_eq!(format!("{:.5}", Kmer::new(reverse)), "ACGTT");
+
+        let seq = b"ACGTACGTACGTGTACACGTACGTACGTGTA";
+        let (kmer, reverse) = Kmer::build_kmer_pair(seq, &[40; 31], 40, 31).unwrap();
+        assert_eq!(Kmer::reverse_complement(reverse, 31), kmer);
+        assert_eq!(
+            format!("{:.31}", Kmer::new(kmer)),
+            "ACGTACGTACGTGTACACGTACGTACGTGTA"
+        );
+        assert_eq!(
+            format!("{:.31}", Kmer::new(reverse)),
+            "TACACGTACGTACGTGTACACGTACGTACGT"
+        );
+    }
+
     #[test]
     fn test_build_kmer_pair() {
         let seq = b"ACGTACGTACGTGTAC";
         let qual = [
             40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40,
         ];
-        let (kmer, reverse_complement) = Kmer::build_kmer_pair(seq, &qual, 40).unwrap();
+        let (kmer, reverse_complement) = Kmer::build_kmer_pair(seq, &qual, 40, 16).unwrap();
         assert_eq!(kmer, 0x1B1B1BB1);
-        assert_eq!(
-            reverse_complement,
-            Kmer::reverse_by_two_bit_groups_u32(!kmer)
-        );
+        assert_eq!(reverse_complement, Kmer::reverse_complement(kmer, 16));
 
         let qual = [
             40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 39,
         ];
-        assert_eq!(Kmer::build_kmer_pair(seq, &qual, 40), None);
+        assert_eq!(Kmer::build_kmer_pair(seq, &qual, 40, 16), None);
     }
 
     #[test]
@@ -229,7 +352,7 @@ mod tests {
             40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40,
             40, 40, 40, 40, 40, 40, 40, 40, 40, 40,
         ];
-        let kmers = Kmer::_kmers_from_record_de_novo(seq, &qual, 40);
+        let kmers = Kmer::_kmers_from_record_de_novo(seq, &qual, 40, 16);
         assert_eq!(
             kmers,
             [
@@ -246,7 +369,14 @@ mod tests {
             40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40,
             40, 40, 40, 40, 40, 40, 40, 40, 40, 40,
         ];
-        let kmers = Kmer::kmers_from_record_incremental(seq, &qual, 40);
+        let kmers = Kmer::kmers_from_record_incremental(
+            seq,
+            &qual,
+            40,
+            16,
+            &KmerSampling::All,
+            BadBasePolicy::SkipWindow,
+        );
         assert_eq!(
             kmers,
             [
@@ -255,4 +385,57 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_kmers_from_record_incremental_bad_bases() {
+        let seq = b"ACGTANGTACGTGTACACGTACGTACGTGTAC";
+        let qual = [40; 32];
+        let all = &KmerSampling::All;
+        let skip = BadBasePolicy::SkipWindow;
+        let abandon = BadBasePolicy::AbandonRead;
+
+        // The N at position 5 leaves 26 good bases after it, for 11 kmers
+        let kmers = Kmer::kmers_from_record_incremental(seq, &qual, 40, 16, all, skip);
+        let expected =
+            Kmer::kmers_from_record_incremental(&seq[6..], &qual[6..], 40, 16, all, skip);
+        assert_eq!(kmers, expected);
+        assert!(!kmers.is_empty());
+        assert!(Kmer::kmers_from_record_incremental(seq, &qual, 40, 16, all, abandon).is_empty());
+
+        // A bad base late in the read keeps the kmers before it in both modes
+        let seq = b"ACGTACGTACGTGTACACGTACGTACGTGTACAACCGGTTAGCTAGCA";
+        let mut qual = [40; 48];
+        qual[20] = 10;
+        let skipped = Kmer::kmers_from_record_incremental(seq, &qual, 40, 16, all, skip);
+        let abandoned = Kmer::kmers_from_record_incremental(seq, &qual, 40, 16, all, abandon);
+        assert_eq!(abandoned.len(), 5);
+        assert!(abandoned.iter().all(|kmer| skipped.contains(kmer)));
+        assert!(skipped.len() > abandoned.len());
+    }
+
+    #[test]
+    fn test_read_shorter_than_kmer() {
+        let all = &KmerSampling::All;
+        let skip = BadBasePolicy::SkipWindow;
+        assert!(
+            Kmer::kmers_from_record_incremental(b"ACGT", &[40; 4], 40, 16, all, skip).is_empty()
+        );
+        assert!(Kmer::_kmers_from_record_de_novo(b"ACGT", &[40; 4], 40, 16).is_empty());
+    }
+
+    #[test]
+    fn test_shard() {
+        assert_eq!(Kmer::new(12345).shard(1), 0);
+        assert!((0..1000).all(|kmer| Kmer::new(kmer).shard(16) < 16));
+
+        // Kmers sharing a long prefix, e.g. from poly-A runs, still spread over all shards
+        let mut counts = [0; 16];
+        for kmer in 0..(1 << 12) {
+            counts[Kmer::new(kmer).shard(16)] += 1;
+        }
+        assert!(
+            counts.iter().all(|&count| (192..=320).contains(&count)),
+            "{counts:?}"
+        );
+    }
 }
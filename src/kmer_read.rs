@@ -1,9 +1,11 @@
-use crate::data_bucket::{BucketDataRead, BucketDataWrite};
+use crate::bucket_codec::{read_varint, write_varint};
+use crate::bucket_header::RecordType;
+use crate::data_bucket::{BucketDataRead, BucketDataWrite, BucketRecord};
 use crate::KmerBits;
 use crate::{kmer::Kmer, ReadId};
 use anyhow::Result;
-use std::io::{BufReader, Read, Write};
-use std::{cmp::Ordering, fs::File, io::BufWriter};
+use std::cmp::Ordering;
+use std::io::{Read, Write};
 
 /// A kmer paired with a read id.
 #[derive(Debug, Default, Clone)]
@@ -29,23 +31,56 @@ impl KmerRead {
     }
 }
 
+impl BucketRecord for KmerRead {
+    const RECORD_TYPE: RecordType = RecordType::KmerRead;
+}
+
 impl BucketDataWrite for KmerRead {
     #[inline(always)]
-    fn write(&self, buffer: &mut BufWriter<File>) -> Result<()> {
+    fn write<W: Write>(&self, buffer: &mut W) -> Result<()> {
         buffer.write_all(&self.kmer().to_le_bytes())?;
         buffer.write_all(&self.read_id().to_le_bytes())?;
         Ok(())
     }
+
+    /// The kmer as difference to the previous kmer; the read id as difference
+    /// to the previous read id if the kmer is the same, otherwise as is.
+    #[inline(always)]
+    fn write_delta<W: Write>(&self, previous: &Self, buffer: &mut W) -> Result<()> {
+        let kmer_delta = self.kmer.bits().wrapping_sub(previous.kmer.bits());
+        write_varint(buffer, kmer_delta)?;
+        let read_id = if kmer_delta == 0 {
+            self.read_id.wrapping_sub(previous.read_id)
+        } else {
+            self.read_id
+        };
+        write_varint(buffer, read_id as u64)?;
+        Ok(())
+    }
 }
 
 impl BucketDataRead for KmerRead {
     #[inline(always)]
-    fn read(&mut self, file_buffer: &mut BufReader<File>) -> Result<()> {
+    fn read<R: Read>(&mut self, file_buffer: &mut R) -> Result<()> {
+        let mut kmer_buffer = [0; 8];
+        file_buffer.read_exact(&mut kmer_buffer[..])?;
+        self.kmer = Kmer::new(KmerBits::from_le_bytes(kmer_buffer));
         let mut buffer = [0; 4];
         file_buffer.read_exact(&mut buffer[..])?;
-        self.kmer = Kmer::new(KmerBits::from_le_bytes(buffer));
-        file_buffer.read_exact(&mut buffer[..])?;
-        self.read_id = KmerBits::from_le_bytes(buffer);
+        self.read_id = ReadId::from_le_bytes(buffer);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn read_delta<R: Read>(&mut self, file_buffer: &mut R) -> Result<()> {
+        let kmer_delta = read_varint(file_buffer)?;
+        let read_id = read_varint(file_buffer)? as ReadId;
+        if kmer_delta == 0 {
+            self.read_id = self.read_id.wrapping_add(read_id);
+        } else {
+            self.kmer = Kmer::new(self.kmer.bits().wrapping_add(kmer_delta));
+            self.read_id = read_id;
+        }
         Ok(())
     }
 }
@@ -74,3 +109,33 @@ impl PartialEq for KmerRead {
         self.kmer == other.kmer && self.read_id == other.read_id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let kmer_reads = [
+            KmerRead::new(Kmer::new(5), 3),
+            KmerRead::new(Kmer::new(5), 3),
+            KmerRead::new(Kmer::new(5), 9),
+            KmerRead::new(Kmer::new(KmerBits::MAX), 1),
+            KmerRead::new(Kmer::new(KmerBits::MAX), ReadId::MAX),
+        ];
+        let mut buffer = Vec::new();
+        let mut previous = KmerRead::default();
+        for kmer_read in &kmer_reads {
+            kmer_read.write_delta(&previous, &mut buffer).unwrap();
+            previous = kmer_read.clone();
+        }
+
+        let mut reader = &buffer[..];
+        let mut kmer_read = KmerRead::default();
+        for expected in &kmer_reads {
+            kmer_read.read_delta(&mut reader).unwrap();
+            assert_eq!(&kmer_read, expected);
+        }
+        assert!(reader.is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{kmer::mix_hash, KmerBits};
+use anyhow::{anyhow, Result};
+use std::{fmt, str::FromStr};
+
+/// Which of the kmers of a read are emitted.
+/// Sampling keeps the kmers that overlapping reads have in common, but writes far fewer of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KmerSampling {
+    /// Every kmer.
+    #[default]
+    All,
+    /// (w,k)-minimizers: the smallest kmer (by hash) of every `window` consecutive kmers.
+    Minimizer { window: usize },
+    /// Open syncmers: kmers whose smallest s-mer (by hash) is the first one.
+    OpenSyncmer { smer_length: usize },
+    /// Closed syncmers: kmers whose smallest s-mer (by hash) is the first or the last one.
+    ClosedSyncmer { smer_length: usize },
+}
+
+impl KmerSampling {
+    pub fn validate(&self, bases_per_kmer: usize) -> Result<()> {
+        match self {
+            Self::All => Ok(()),
+            Self::Minimizer { window } if *window > 0 => Ok(()),
+            Self::Minimizer { .. } => Err(anyhow!("Minimizer window must be at least 1")),
+            Self::OpenSyncmer { smer_length } | Self::ClosedSyncmer { smer_length } => {
+                if *smer_length == 0 || *smer_length >= bases_per_kmer {
+                    Err(anyhow!(
+                        "Syncmer s-mer length must be between 1 and {}",
+                        bases_per_kmer - 1
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Samples from the canonical kmers of a read, which must be in sequence order.
+    pub fn sample(&self, kmers: &[KmerBits], bases_per_kmer: usize) -> Vec<KmerBits> {
+        match self {
+            Self::All => kmers.to_vec(),
+            Self::Minimizer { window } => Self::minimizers(kmers, *window),
+            Self::OpenSyncmer { smer_length } => kmers
+                .iter()
+                .filter(|kmer| {
+                    let pos = Self::smallest_smer_position(**kmer, bases_per_kmer, *smer_length);
+                    pos == 0
+                })
+                .copied()
+                .collect(),
+            Self::ClosedSyncmer { smer_length } => kmers
+                .iter()
+                .filter(|kmer| {
+                    let pos = Self::smallest_smer_position(**kmer, bases_per_kmer, *smer_length);
+                    pos == 0 || pos == bases_per_kmer - smer_length
+                })
+                .copied()
+                .collect(),
+        }
+    }
+
+    fn minimizers(kmers: &[KmerBits], window: usize) -> Vec<KmerBits> {
+        let window = window.min(kmers.len()).max(1);
+        let mut ret = Vec::new();
+        let mut last_pos = None;
+        for (start, window_kmers) in kmers.windows(window).enumerate() {
+            let offset = (0..window_kmers.len())
+                .min_by_key(|i| mix_hash(window_kmers[*i]))
+                .unwrap_or(0);
+            if last_pos != Some(start + offset) {
+                last_pos = Some(start + offset);
+                ret.push(window_kmers[offset]);
+            }
+        }
+        ret
+    }
+
+    /// Position of the smallest s-mer within the kmer, counted from its first base.
+    #[inline(always)]
+    fn smallest_smer_position(kmer: KmerBits, bases_per_kmer: usize, smer_length: usize) -> usize {
+        let smer_mask = KmerBits::MAX >> (KmerBits::BITS as usize - 2 * smer_length);
+        let number_of_smers = bases_per_kmer - smer_length + 1;
+        (0..number_of_smers)
+            .min_by_key(|pos| {
+                let smer = (kmer >> (2 * (number_of_smers - pos - 1))) & smer_mask;
+                mix_hash(smer)
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Parses `all`, `minimizer:<w>`, `open_syncmer:<s>` or `closed_syncmer:<s>`.
+impl FromStr for KmerSampling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name, Some(value.parse::<usize>()?)),
+            None => (s, None),
+        };
+        match (name, value) {
+            ("all", None) => Ok(Self::All),
+            ("minimizer", Some(window)) => Ok(Self::Minimizer { window }),
+            ("open_syncmer", Some(smer_length)) => Ok(Self::OpenSyncmer { smer_length }),
+            ("closed_syncmer", Some(smer_length)) => Ok(Self::ClosedSyncmer { smer_length }),
+            _ => Err(anyhow!("Unknown kmer sampling '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for KmerSampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => write!(f, "all"),
+            Self::Minimizer { window } => write!(f, "minimizer:{window}"),
+            Self::OpenSyncmer { smer_length } => write!(f, "open_syncmer:{smer_length}"),
+            Self::ClosedSyncmer { smer_length } => write!(f, "closed_syncmer:{smer_length}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kmer::{BadBasePolicy, Kmer};
+
+    #[test]
+    fn test_sample() {
+        let kmers = (0..100).map(|i| i * 7919).collect::<Vec<KmerBits>>();
+        assert_eq!(KmerSampling::All.sample(&kmers, 16), kmers);
+
+        // Every window of 10 contains at least one minimizer
+        let minimizers = KmerSampling::Minimizer { window: 10 }.sample(&kmers, 16);
+        assert!(minimizers.len() < kmers.len() / 2);
+        for window in kmers.windows(10) {
+            assert!(window.iter().any(|kmer| minimizers.contains(kmer)));
+        }
+
+        let open = KmerSampling::OpenSyncmer { smer_length: 8 }.sample(&kmers, 16);
+        let closed = KmerSampling::ClosedSyncmer { smer_length: 8 }.sample(&kmers, 16);
+        assert!(open.iter().all(|kmer| closed.contains(kmer)));
+        assert!(closed.len() < kmers.len());
+
+        assert_eq!(
+            "minimizer:5".parse::<KmerSampling>().unwrap(),
+            KmerSampling::Minimizer { window: 5 }
+        );
+        assert!("minimizer".parse::<KmerSampling>().is_err());
+        assert!(KmerSampling::OpenSyncmer { smer_length: 16 }
+            .validate(16)
+            .is_err());
+    }
+
+    #[test]
+    fn test_sample_overlapping_reads() {
+        // Reads overlapping by 100 bases, one of them from the reverse strand
+        let mut state = 42u64;
+        let genome = (0..300)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                b"ACGT"[(state >> 62) as usize]
+            })
+            .collect::<Vec<_>>();
+        let read1 = &genome[..200];
+        let read2 = genome[100..]
+            .iter()
+            .rev()
+            .map(|base| match base {
+                b'A' => b'T',
+                b'C' => b'G',
+                b'G' => b'C',
+                _ => b'A',
+            })
+            .collect::<Vec<_>>();
+        let kmers = |sequence: &[u8], sampling: &KmerSampling| {
+            Kmer::kmers_from_record_incremental(
+                sequence,
+                &vec![40; sequence.len()],
+                20,
+                16,
+                sampling,
+                BadBasePolicy::SkipWindow,
+            )
+        };
+
+        let all = kmers(read1, &KmerSampling::All);
+        for sampling in [
+            KmerSampling::Minimizer { window: 10 },
+            KmerSampling::OpenSyncmer { smer_length: 8 },
+            KmerSampling::ClosedSyncmer { smer_length: 8 },
+        ] {
+            let sampled1 = kmers(read1, &sampling);
+            let sampled2 = kmers(&read2, &sampling);
+            assert!(sampled1.len() < all.len() / 2);
+            assert!(sampled1.iter().all(|kmer| all.contains(kmer)));
+            assert!(sampled1.iter().any(|kmer| sampled2.contains(kmer)));
+
+            for kmer in sampled1.iter().chain(&sampled2) {
+                let position = KmerSampling::smallest_smer_position(*kmer, 16, 8);
+                match sampling {
+                    KmerSampling::OpenSyncmer { .. } => assert_eq!(position, 0),
+                    KmerSampling::ClosedSyncmer { .. } => assert!(position == 0 || position == 8),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
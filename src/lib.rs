@@ -0,0 +1,66 @@
+//! Groups sequencing reads that share kmers.
+//!
+//! The pipeline has three stages, each driven by [`ReadGrouper`]:
+//! 1. [`ReadGrouper::read_bam_file`] or [`ReadGrouper::read_fastq_file`] write the kmers
+//!    of every read into sorted bucket files of [`KmerRead`]s.
+//! 2. [`ReadGrouper::process_read_kmer_buckets`] merges those, and writes a [`ReadPairKmer`]
+//!    for every pair of reads sharing a kmer.
+//! 3. [`ReadGrouper::group_reads`] counts the kmers shared by each read pair, and joins
+//!    reads into [`ReadGroups`].
+//!
+//! Each stage returns a [`BucketList`] describing the files it wrote.
+//! [`GroupExporter`] then writes the records of a BAM file by group.
+
+mod bucket_codec;
+mod bucket_header;
+mod bucket_list;
+mod buf_reader_entry;
+mod data_bucket;
+mod fastq_reader;
+mod genomic_region;
+mod group_export;
+mod kmer;
+mod kmer_read;
+mod kmer_sampling;
+mod min_max_reads;
+mod multi_buf_reader;
+mod read_grouper;
+mod read_grouper_config;
+mod read_groups;
+mod read_id_assigner;
+mod read_index;
+mod read_pair_kmer;
+mod read_stats;
+mod sharded_bucket;
+mod shared_kmer_counter;
+#[cfg(test)]
+mod test_bam;
+mod union_find;
+
+pub use bucket_codec::BucketCodec;
+pub use bucket_header::{BucketHeader, RecordType};
+pub use bucket_list::BucketList;
+pub use data_bucket::{BucketDataRead, BucketDataWrite, BucketRecord, DataBucket};
+pub use fastq_reader::{FastqReader, FastqRecord, DEFAULT_PHRED_OFFSET};
+pub use genomic_region::GenomicRegion;
+pub use group_export::{
+    GroupExport, GroupExporter, DEFAULT_GROUP_SIZE_TAG, DEFAULT_GROUP_TAG, DEFAULT_MAX_OPEN_FILES,
+    DEFAULT_MIN_GROUP_SIZE,
+};
+pub use kmer::{BadBasePolicy, Kmer};
+pub use kmer_read::KmerRead;
+pub use kmer_sampling::KmerSampling;
+pub use min_max_reads::MinMaxReads;
+pub use multi_buf_reader::MultiBufReader;
+pub use read_grouper::{ReadGrouper, SkipReason, DEFAULT_EXCLUDE_FLAGS};
+pub use read_grouper_config::{CleanupPolicy, ReadGrouperConfig};
+pub use read_groups::ReadGroups;
+pub use read_id_assigner::MateMode;
+pub use read_index::ReadIndex;
+pub use read_pair_kmer::ReadPairKmer;
+pub use read_stats::ReadStats;
+
+/// A kmer of up to 32 bases, two bits per base.
+pub type KmerBits = u64;
+/// Index of a read (or read pair, depending on the `MateMode`) in the input file.
+pub type ReadId = u32;
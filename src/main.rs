@@ -1,35 +1,458 @@
-mod bucket_list;
-mod buf_reader_entry;
-mod data_bucket;
-mod kmer;
-mod kmer_read;
-mod min_max_reads;
-mod multi_buf_reader;
-mod read_grouper;
-mod read_pair_kmer;
-
-use min_max_reads::MinMaxReads;
-use read_grouper::ReadGrouper;
-
-pub type KmerBits = u32;
-pub type ReadId = u32;
-
-fn main() {
-    let rg = ReadGrouper::new("/Users/mm6/rust/read_grouper/buckets");
-    let bucket_list = rg
-        .read_bam_file("/Users/mm6/rust/read_grouper/SRR9217386.sorted.bam")
-        .unwrap();
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use read_grouper::{
+    BadBasePolicy, BucketCodec, BucketList, CleanupPolicy, FastqReader, GenomicRegion, GroupExport,
+    GroupExporter, KmerSampling, MateMode, MinMaxReads, ReadGrouper, ReadGrouperConfig, ReadGroups,
+    DEFAULT_MAX_OPEN_FILES, DEFAULT_MIN_GROUP_SIZE, DEFAULT_PHRED_OFFSET,
+};
+use std::{collections::HashMap, fs};
+
+/// Groups sequencing reads that share kmers.
+///
+/// `run` does everything at once. Alternatively, `kmerize`, `pair` and `group` run one
+/// stage each, passing bucket files on via TOML manifests.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Writes the kmers of all reads of a BAM or FASTQ file into bucket files
+    Kmerize {
+        /// Input BAM or (gzipped) FASTQ file
+        #[arg(short, long)]
+        input: String,
+        /// Manifest of the kmer buckets to write
+        #[arg(short, long)]
+        manifest: String,
+        #[command(flatten)]
+        kmers: KmerArgs,
+        #[command(flatten)]
+        buckets: BucketArgs,
+    },
+    /// Finds all read pairs sharing a kmer
+    Pair {
+        /// Manifest of the kmer buckets, from `kmerize`
+        #[arg(short, long)]
+        input: String,
+        /// Manifest of the read pair buckets to write
+        #[arg(short, long)]
+        manifest: String,
+        #[command(flatten)]
+        group_sizes: GroupSizeArgs,
+        #[command(flatten)]
+        buckets: BucketArgs,
+    },
+    /// Groups reads sharing enough kmers, and writes the groups as TSV
+    Group {
+        /// Manifest of the read pair buckets, from `pair`
+        #[arg(short, long)]
+        input: String,
+        /// Output TSV file
+        #[arg(short, long)]
+        output: String,
+        #[command(flatten)]
+        grouping: GroupingArgs,
+        #[command(flatten)]
+        buckets: BucketArgs,
+    },
+    /// Writes the records of a BAM file by group, per-group files or one tagged BAM
+    Export {
+        /// Input BAM file, as given to `kmerize` or `run`
+        #[arg(short, long)]
+        input: String,
+        /// Groups TSV, from `group` or `run`
+        #[arg(short, long)]
+        groups: String,
+        /// Manifest of the run that made the groups, from `kmerize`, `pair` or `run`;
+        /// its mate mode and regions number the reads as in that run
+        #[arg(short, long)]
+        manifest: String,
+        /// Output file prefix; per-group files are named `<prefix><group_id>.bam` or `.fastq`
+        #[arg(short, long)]
+        output: String,
+        /// Output format: bam, fastq or tagged_bam[:<group tag>[,<size tag>]], e.g. tagged_bam:XG,XZ;
+        /// tagged_bam:RG writes each group as a read group
+        #[arg(short, long, default_value = "bam")]
+        format: GroupExport,
+        /// Groups with fewer reads get no file of their own
+        #[arg(long, default_value_t = DEFAULT_MIN_GROUP_SIZE)]
+        min_group_size: usize,
+        /// Maximum number of per-group files written in one pass over the input
+        #[arg(long, default_value_t = DEFAULT_MAX_OPEN_FILES)]
+        max_open_files: usize,
+    },
+    /// Prints the read statistics of a manifest
+    Stats {
+        /// Manifest from `kmerize` or `pair`
+        #[arg(short, long)]
+        input: String,
+    },
+    /// Runs all stages, from the input file to the groups TSV
+    Run {
+        /// Input BAM or (gzipped) FASTQ file
+        #[arg(short, long)]
+        input: String,
+        /// Output TSV file
+        #[arg(short, long)]
+        output: String,
+        /// Manifest of the read pair buckets to write, with the settings used
+        #[arg(short, long)]
+        manifest: Option<String>,
+        #[command(flatten)]
+        kmers: KmerArgs,
+        #[command(flatten)]
+        group_sizes: GroupSizeArgs,
+        #[command(flatten)]
+        grouping: GroupingArgs,
+        #[command(flatten)]
+        buckets: BucketArgs,
+    },
+}
+
+/// Settings for writing and merging bucket files.
+/// Options given on the command line override those in the config file.
+#[derive(Args)]
+struct BucketArgs {
+    /// TOML file with `ReadGrouper` settings; defaults to the settings of the input manifest, if any
+    #[arg(short, long)]
+    config: Option<String>,
+    /// Directory for the bucket files
+    #[arg(short, long)]
+    bucket_dir: Option<String>,
+    /// Maximum number of entries held in memory before they are written to disk
+    #[arg(long)]
+    bucket_size: Option<usize>,
+    /// Bucket file encoding: raw, delta, delta+zstd or delta+lz4
+    #[arg(long)]
+    codec: Option<BucketCodec>,
+    /// Number of kmer shards bucketed and merged in parallel
+    #[arg(long)]
+    shards: Option<usize>,
+    /// Maximum number of bucket files open at once, shared by the shards merged in parallel
+    #[arg(long)]
+    merge_fan_in: Option<usize>,
+    /// Threads for merging shards; 0 uses one per core
+    #[arg(short, long)]
+    threads: Option<usize>,
+    /// What happens to bucket files once they have been read: keep or delete_consumed
+    #[arg(long)]
+    cleanup: Option<CleanupPolicy>,
+}
+
+impl BucketArgs {
+    /// The config file if given, else `base`, else the defaults; with command line overrides.
+    fn config(&self, base: Option<&ReadGrouperConfig>) -> Result<ReadGrouperConfig> {
+        let mut config = match (&self.config, base) {
+            (Some(filename), _) => ReadGrouperConfig::from_toml_file(filename)?,
+            (None, Some(base)) => base.clone(),
+            (None, None) => ReadGrouperConfig::default(),
+        };
+        if let Some(bucket_dir) = &self.bucket_dir {
+            config.bucket_dir.clone_from(bucket_dir);
+        }
+        if let Some(bucket_size) = self.bucket_size {
+            config.max_bucket_size = bucket_size;
+        }
+        if let Some(codec) = self.codec {
+            config.bucket_codec = codec;
+        }
+        if let Some(shards) = self.shards {
+            config.shards = shards;
+        }
+        if let Some(merge_fan_in) = self.merge_fan_in {
+            config.merge_fan_in = merge_fan_in;
+        }
+        if let Some(threads) = self.threads {
+            config.threads = threads;
+        }
+        if let Some(cleanup) = self.cleanup {
+            config.cleanup = cleanup;
+        }
+        Ok(config)
+    }
+
+    /// Like `config`, for a stage reading `bucket_list`; the number of shards is that of the
+    /// manifest, as the buckets were already split that way.
+    fn stage_config(&self, bucket_list: &BucketList) -> Result<ReadGrouperConfig> {
+        let mut config = self.config(Some(bucket_list.config()))?;
+        let shards = bucket_list.shards().len();
+        if self.shards.is_some_and(|requested| requested != shards) {
+            return Err(anyhow!(
+                "The input manifest has {shards} shards; --shards cannot change that after kmerize"
+            ));
+        }
+        config.shards = shards;
+        // The reads were numbered with these; later manifests must repeat them for `export`
+        config.mate_mode = bucket_list.config().mate_mode;
+        config.regions.clone_from(&bucket_list.config().regions);
+        Ok(config)
+    }
+}
+
+/// Settings for generating the kmers of each read.
+#[derive(Args)]
+struct KmerArgs {
+    /// Kmers covering a base of lower quality are skipped
+    #[arg(short = 'q', long)]
+    min_base_quality: Option<u8>,
+    /// Kmer length, 1-32
+    #[arg(short, long)]
+    kmer_length: Option<usize>,
+    /// Which kmers to use: all, minimizer:<w>, open_syncmer:<s> or closed_syncmer:<s>
+    #[arg(long)]
+    kmer_sampling: Option<KmerSampling>,
+    /// What a low-quality base skips: skip_window or abandon_read
+    #[arg(long)]
+    bad_base_policy: Option<BadBasePolicy>,
+    /// How mates get read ids: separate, by_name, adjacent or linked
+    #[arg(long)]
+    mate_mode: Option<MateMode>,
+    /// BAM records lacking any of these SAM flags are skipped, e.g. 0x2
+    #[arg(long, value_parser = parse_flags)]
+    include_flags: Option<u16>,
+    /// BAM records with any of these SAM flags are skipped; default 0xF00
+    #[arg(long, value_parser = parse_flags)]
+    exclude_flags: Option<u16>,
+    /// BAM records with a lower mapping quality are skipped
+    #[arg(long)]
+    min_mapq: Option<u8>,
+    /// Quality offset of FASTQ input
+    #[arg(long, default_value_t = DEFAULT_PHRED_OFFSET)]
+    phred_offset: u8,
+    #[command(flatten)]
+    regions: RegionArgs,
+}
+
+impl KmerArgs {
+    fn apply(&self, config: &mut ReadGrouperConfig) {
+        if let Some(min_base_quality) = self.min_base_quality {
+            config.min_base_quality = min_base_quality;
+        }
+        if let Some(kmer_length) = self.kmer_length {
+            config.bases_per_kmer = kmer_length;
+        }
+        if let Some(kmer_sampling) = self.kmer_sampling {
+            config.kmer_sampling = kmer_sampling;
+        }
+        if let Some(bad_base_policy) = self.bad_base_policy {
+            config.bad_base_policy = bad_base_policy;
+        }
+        if let Some(mate_mode) = self.mate_mode {
+            config.mate_mode = mate_mode;
+        }
+        if let Some(include_flags) = self.include_flags {
+            config.include_flags = include_flags;
+        }
+        if let Some(exclude_flags) = self.exclude_flags {
+            config.exclude_flags = exclude_flags;
+        }
+        if let Some(min_mapq) = self.min_mapq {
+            config.min_mapq = min_mapq;
+        }
+    }
+
+    fn kmerize(&self, buckets: &BucketArgs, input: &str) -> Result<(ReadGrouper, BucketList)> {
+        let mut config = buckets.config(None)?;
+        self.apply(&mut config);
+        let regions = self.regions.regions()?;
+        if !regions.is_empty() {
+            config.regions = regions;
+        }
+        let rg = read_grouper(&config)?;
+        let bucket_list = if FastqReader::is_fastq_path(input) {
+            rg.read_fastq_file(input, self.phred_offset)
+        } else {
+            rg.read_bam_file(input)
+        }?;
+        Ok((rg, bucket_list))
+    }
+}
+
+/// Restricts BAM input to reads overlapping genomic regions, via its BAM index.
+#[derive(Args)]
+struct RegionArgs {
+    /// Region to read, as chr:start-end (1-based, inclusive) or chr; may be repeated
+    #[arg(short, long = "region")]
+    regions: Vec<GenomicRegion>,
+    /// BED file of regions to read
+    #[arg(long)]
+    bed: Option<String>,
+}
+
+impl RegionArgs {
+    fn regions(&self) -> Result<Vec<GenomicRegion>> {
+        let mut regions = self.regions.clone();
+        if let Some(bed) = &self.bed {
+            regions.extend(GenomicRegion::from_bed_file(bed)?);
+        }
+        Ok(regions)
+    }
+}
+
+/// Parses SAM flags as decimal, or hexadecimal with a `0x` prefix.
+fn parse_flags(s: &str) -> Result<u16, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Settings for which kmers pair their reads.
+#[derive(Args)]
+struct GroupSizeArgs {
+    /// Kmers shared by fewer reads are ignored
+    #[arg(long, default_value_t = 3)]
+    min_reads: usize,
+    /// Kmers shared by more reads are ignored, as likely repeats
+    #[arg(long, default_value_t = 50)]
+    max_reads: usize,
+}
+
+impl GroupSizeArgs {
+    fn min_max(&self) -> MinMaxReads {
+        MinMaxReads::new(self.min_reads, self.max_reads)
+    }
+}
+
+/// Settings for which read pairs join their groups.
+#[derive(Args)]
+struct GroupingArgs {
+    /// Reads sharing fewer kmers are not grouped
+    #[arg(long, default_value_t = 2)]
+    min_shared_kmers: usize,
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Kmerize {
+            input,
+            manifest,
+            kmers,
+            buckets,
+        } => {
+            let (_rg, bucket_list) = kmers.kmerize(&buckets, &input)?;
+            print_bucket_list_stats(&bucket_list);
+            bucket_list.write_manifest(&manifest)?;
+        }
+        Command::Pair {
+            input,
+            manifest,
+            group_sizes,
+            buckets,
+        } => {
+            let bucket_list = BucketList::read_manifest(&input)?;
+            let rg = read_grouper(&buckets.stage_config(&bucket_list)?)?;
+            let (pair_bucket_list, stats) =
+                rg.process_read_kmer_buckets(&bucket_list, &group_sizes.min_max())?;
+            print_histogram("reads_per_kmer", "occurrences", &stats);
+            pair_bucket_list.write_manifest(&manifest)?;
+        }
+        Command::Group {
+            input,
+            output,
+            grouping,
+            buckets,
+        } => {
+            let pair_bucket_list = BucketList::read_manifest(&input)?;
+            let rg = read_grouper(&buckets.stage_config(&pair_bucket_list)?)?;
+            group(&rg, &pair_bucket_list, &grouping, &output)?;
+        }
+        Command::Export {
+            input,
+            groups,
+            manifest,
+            output,
+            format,
+            min_group_size,
+            max_open_files,
+        } => {
+            let read_groups = ReadGroups::read_tsv(&groups)?;
+            let bucket_list = BucketList::read_manifest(&manifest)?;
+            let mut exporter = GroupExporter::new(&read_groups, &bucket_list)?;
+            exporter.set_min_group_size(min_group_size);
+            exporter.set_max_open_files(max_open_files)?;
+            exporter.set_command_line(&std::env::args().collect::<Vec<_>>().join(" "));
+            let filenames = exporter.export(&input, &format, &output)?;
+            println!("Files written: {}", filenames.len());
+        }
+        Command::Stats { input } => {
+            let bucket_list = BucketList::read_manifest(&input)?;
+            print_bucket_list_stats(&bucket_list);
+            println!("\nConfig:\n{}", toml::to_string(bucket_list.config())?);
+        }
+        Command::Run {
+            input,
+            output,
+            manifest,
+            kmers,
+            group_sizes,
+            grouping,
+            buckets,
+        } => {
+            let (rg, bucket_list) = kmers.kmerize(&buckets, &input)?;
+            print_bucket_list_stats(&bucket_list);
+            let (pair_bucket_list, _stats) =
+                rg.process_read_kmer_buckets(&bucket_list, &group_sizes.min_max())?;
+            if let Some(manifest) = manifest {
+                pair_bucket_list.write_manifest(&manifest)?;
+            }
+            group(&rg, &pair_bucket_list, &grouping, &output)?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates the bucket dir, and a `ReadGrouper` with the given settings.
+fn read_grouper(config: &ReadGrouperConfig) -> Result<ReadGrouper> {
+    fs::create_dir_all(&config.bucket_dir)?;
+    ReadGrouper::from_config(config)
+}
+
+fn group(
+    rg: &ReadGrouper,
+    pair_bucket_list: &BucketList,
+    grouping: &GroupingArgs,
+    output: &str,
+) -> Result<()> {
+    let read_groups = rg.group_reads(pair_bucket_list, grouping.min_shared_kmers)?;
+    println!("Number of groups: {}", read_groups.number_of_groups());
+    read_groups.write_tsv(output)?;
+    print_histogram("group_size", "groups", &read_groups.size_stats());
+    Ok(())
+}
+
+fn print_bucket_list_stats(bucket_list: &BucketList) {
     println!("Sample name: {}", bucket_list.sample_name());
     println!("Number of reads: {}", bucket_list.number_of_reads());
+    println!("Records: {}", bucket_list.read_stats().records());
+    println!(
+        "Records shorter than a kmer: {}",
+        bucket_list.read_stats().too_short()
+    );
+    println!(
+        "Records skipped by flags: {}",
+        bucket_list.read_stats().skipped_by_flags()
+    );
+    println!(
+        "Records skipped by mapping quality: {}",
+        bucket_list.read_stats().skipped_by_mapq()
+    );
+    println!("Shards: {}", bucket_list.shards().len());
     println!("Files: {}", bucket_list.filenames().len());
+    if let Some(read_index) = bucket_list.read_index() {
+        println!("Read index: {read_index}");
+    }
+}
 
-    let (_bucket_list, _stats) = rg
-        .process_read_kmer_buckets(&bucket_list, &MinMaxReads::new(3, 50))
-        .unwrap();
-    // let mut keys = stats.keys().cloned().collect::<Vec<_>>();
-    // keys.sort();
-    // println!("reads_per_kmer\toccurrences");
-    // for key in keys {
-    //     println!("{}\t{}", key, stats[&key]);
-    // }
+fn print_histogram(key_name: &str, value_name: &str, histogram: &HashMap<usize, usize>) {
+    let mut keys = histogram.keys().cloned().collect::<Vec<_>>();
+    keys.sort();
+    println!("{key_name}\t{value_name}");
+    for key in keys {
+        println!("{}\t{}", key, histogram[&key]);
+    }
 }
@@ -1,39 +1,296 @@
-use crate::{buf_reader_entry::BufReaderEntry, data_bucket::BucketDataRead};
+use crate::{
+    buf_reader_entry::BufReaderEntry,
+    data_bucket::{BucketDataRead, BucketDataWrite},
+};
+use anyhow::{anyhow, Result};
+use std::cmp::Reverse;
+use std::collections::binary_heap::{BinaryHeap, PeekMut};
+use std::fs::{self, File};
 
+/// K-way merge of sorted bucket files.
+/// The readers are kept in a min-heap, ordered by their current entry.
+/// Iteration stops after the first error.
 pub struct MultiBufReader<T> {
-    readers: Vec<BufReaderEntry<T>>,
+    readers: BinaryHeap<Reverse<BufReaderEntry<T>>>,
+    failed: bool,
+    intermediate_files: Vec<String>,
 }
 
 impl<T: std::cmp::Ord + BucketDataRead + Default + Clone> MultiBufReader<T> {
-    pub fn new(files: &[String]) -> Self {
-        Self {
-            readers: files
-                .iter()
-                .map(|f| BufReaderEntry::new(f).unwrap())
-                .collect(),
+    /// Opens all files; fails if any of them is missing, invalid or empty.
+    pub fn new(files: &[String]) -> Result<Self> {
+        Ok(Self {
+            readers: Self::open_readers(files)?,
+            failed: false,
+            intermediate_files: Vec::new(),
+        })
+    }
+
+    /// Like `new`, but never has more than `fan_in` files open at once.
+    /// While there are more files, batches of `fan_in` files are merged into intermediate
+    /// runs next to the first file. Intermediate runs are deleted once they have been merged,
+    /// or when the reader is dropped; the input files are kept.
+    pub fn with_fan_in(files: &[String], fan_in: usize) -> Result<Self>
+    where
+        T: BucketDataWrite,
+    {
+        if fan_in < 2 {
+            return Err(anyhow!("Merge fan-in must be at least 2, not {fan_in}"));
         }
+        // Owns the intermediate runs from the start, so they are removed on error
+        let mut ret = Self {
+            readers: BinaryHeap::new(),
+            failed: false,
+            intermediate_files: Vec::new(),
+        };
+        let mut files = files.to_vec();
+        let mut run_id = 0;
+        while files.len() > fan_in {
+            let merged_runs = ret.intermediate_files.clone();
+            let mut runs = Vec::with_capacity(files.len().div_ceil(fan_in));
+            for batch in files.chunks(fan_in) {
+                if let [file] = batch {
+                    runs.push(file.clone());
+                    continue;
+                }
+                let run = format!("{}.merge{run_id}", files[0]);
+                run_id += 1;
+                ret.intermediate_files.push(run.clone());
+                Self::write_run(batch, &run)?;
+                runs.push(run);
+            }
+            // The intermediate runs of the previous pass have been merged
+            ret.intermediate_files.retain(|file| {
+                if merged_runs.contains(file) && !runs.contains(file) {
+                    let _ = fs::remove_file(file);
+                    return false;
+                }
+                true
+            });
+            files = runs;
+        }
+        ret.readers = Self::open_readers(&files)?;
+        Ok(ret)
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.readers.is_empty()
+    fn open_readers(files: &[String]) -> Result<BinaryHeap<Reverse<BufReaderEntry<T>>>> {
+        let readers = files
+            .iter()
+            .map(|f| BufReaderEntry::new(f))
+            .collect::<Result<Vec<BufReaderEntry<T>>>>()?;
+        if let Some(first) = readers.first() {
+            // All buckets of one run must use the same kmer length
+            if let Some(pos) = readers
+                .iter()
+                .position(|r| r.bases_per_kmer() != first.bases_per_kmer())
+            {
+                return Err(anyhow!(
+                    "Bucket file {} has kmer length {}, but {} has {}",
+                    files[pos],
+                    readers[pos].bases_per_kmer(),
+                    files[0],
+                    first.bases_per_kmer()
+                ));
+            }
+        }
+        Ok(readers.into_iter().map(Reverse).collect())
     }
 
-    // TODO as iterator?
-    pub fn next(&mut self) -> Option<T> {
-        // Find the next entry to process
-        let min_key = self
+    /// Merges `files` into a single sorted bucket file, with the header of the first file.
+    fn write_run(files: &[String], filename: &str) -> Result<()>
+    where
+        T: BucketDataWrite,
+    {
+        let mbr = Self::new(files)?;
+        let entry_count = mbr
             .readers
             .iter()
-            .enumerate()
-            .min_by_key(|&(_, value)| value)
-            .map(|(key, _)| key)?;
-        let ret = (*self.readers[min_key].last_entry_read()).clone();
+            .map(|reader| reader.0.header().entry_count())
+            .sum();
+        let header = match mbr.readers.peek() {
+            Some(reader) => reader.0.header().for_file(T::RECORD_TYPE, entry_count),
+            None => return Err(anyhow!("No bucket files to merge into {filename}")),
+        };
+        header
+            .write_file(File::create(filename)?, |mut buffer| {
+                let mut previous = T::default();
+                for entry in mbr {
+                    let entry = entry?;
+                    if header.codec().is_delta() {
+                        entry.write_delta(&previous, &mut buffer)?;
+                    } else {
+                        entry.write(&mut buffer)?;
+                    }
+                    previous = entry;
+                }
+                Ok(())
+            })
+            .map_err(|e| anyhow!("Could not write intermediate run {filename}: {e}"))
+    }
+}
 
-        // Remove reader if it has no more entries
-        if self.readers[min_key].read_next_entry_failed() {
-            self.readers.remove(min_key);
+impl<T: std::cmp::Ord + BucketDataRead + Default + Clone> Iterator for MultiBufReader<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
         }
 
-        Some(ret)
+        // The reader with the smallest entry is at the top of the heap
+        let mut min_reader = self.readers.peek_mut()?;
+        let ret = min_reader.0.last_entry_read().clone();
+
+        // Remove reader if it has no more entries; otherwise, it moves to its new heap position
+        match min_reader.0.read_next_entry() {
+            Ok(true) => {}
+            Ok(false) => {
+                PeekMut::pop(min_reader);
+            }
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(ret))
+    }
+}
+
+impl<T> Drop for MultiBufReader<T> {
+    fn drop(&mut self) {
+        self.readers.clear(); // Close the files before removing them
+        for filename in &self.intermediate_files {
+            let _ = fs::remove_file(filename);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bucket_codec::BucketCodec, bucket_header::BucketHeader, data_bucket::DataBucket,
+        kmer::Kmer, kmer_read::KmerRead,
+    };
+
+    #[test]
+    fn test_merge() {
+        let bucket_dir = std::env::temp_dir().join("read_grouper_test_merge");
+        let _ = std::fs::remove_dir_all(&bucket_dir);
+        std::fs::create_dir_all(&bucket_dir).unwrap();
+        let header = BucketHeader::new(16, BucketCodec::Delta, "test");
+        let mut bucket = DataBucket::new(5, bucket_dir.to_str().unwrap(), "test", "pairs", &header);
+        let mut expected = Vec::new();
+        for i in 0..23u32 {
+            let kmer_read = KmerRead::new(Kmer::new((i * 7 % 11) as u64), i);
+            expected.push(kmer_read.clone());
+            bucket.add(kmer_read);
+        }
+        let filenames = bucket.finish().unwrap();
+        assert_eq!(filenames.len(), 5);
+
+        let mbr: MultiBufReader<KmerRead> = MultiBufReader::new(&filenames).unwrap();
+        let merged = mbr.collect::<Result<Vec<_>>>().unwrap();
+        expected.sort();
+        assert_eq!(merged, expected);
+        std::fs::remove_dir_all(&bucket_dir).unwrap();
+    }
+
+    #[test]
+    fn test_multi_pass_merge() {
+        let bucket_dir = std::env::temp_dir().join("read_grouper_test_multi_pass_merge");
+        let _ = std::fs::remove_dir_all(&bucket_dir);
+        std::fs::create_dir_all(&bucket_dir).unwrap();
+        let header = BucketHeader::new(16, BucketCodec::DeltaZstd, "test");
+        let mut bucket = DataBucket::new(2, bucket_dir.to_str().unwrap(), "test", "pairs", &header);
+        let mut expected = Vec::new();
+        for i in 0..23u32 {
+            let kmer_read = KmerRead::new(Kmer::new((i * 7 % 11) as u64), i);
+            expected.push(kmer_read.clone());
+            bucket.add(kmer_read);
+        }
+        let filenames = bucket.finish().unwrap();
+        assert_eq!(filenames.len(), 12);
+        assert!(MultiBufReader::<KmerRead>::with_fan_in(&filenames, 1).is_err());
+
+        // 12 files => 4 runs => 2 runs
+        let mbr: MultiBufReader<KmerRead> = MultiBufReader::with_fan_in(&filenames, 3).unwrap();
+        assert_eq!(std::fs::read_dir(&bucket_dir).unwrap().count(), 12 + 2);
+        let merged = mbr.collect::<Result<Vec<_>>>().unwrap();
+        expected.sort();
+        assert_eq!(merged, expected);
+        assert_eq!(std::fs::read_dir(&bucket_dir).unwrap().count(), 12);
+        std::fs::remove_dir_all(&bucket_dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_entries() {
+        // Valid header and checksum, but fewer entries than the header claims
+        let filename = std::env::temp_dir().join("read_grouper_test_missing_entries.pairs");
+        let header = BucketHeader::new(16, BucketCodec::Raw, "test")
+            .for_file(crate::bucket_header::RecordType::KmerRead, 3);
+        header
+            .write_file(std::fs::File::create(&filename).unwrap(), |mut buffer| {
+                KmerRead::new(Kmer::new(1), 1).write(&mut buffer)?;
+                KmerRead::new(Kmer::new(2), 2).write(&mut buffer)?;
+                Ok(())
+            })
+            .unwrap();
+
+        let filenames = vec![filename.to_str().unwrap().to_string()];
+        let mut mbr: MultiBufReader<KmerRead> = MultiBufReader::new(&filenames).unwrap();
+        assert!(mbr.next().unwrap().is_ok());
+        let e = mbr.next().unwrap().unwrap_err();
+        assert!(e.to_string().contains("truncated or corrupt"));
+        assert!(mbr.next().is_none());
+
+        assert!(MultiBufReader::<KmerRead>::new(&["/nonexistent.pairs".to_string()]).is_err());
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        // The checksum is verified once the whole payload has been read, for every codec
+        let filename = std::env::temp_dir().join("read_grouper_test_checksum_mismatch.pairs");
+        let filenames = vec![filename.to_str().unwrap().to_string()];
+        for codec in [
+            BucketCodec::Raw,
+            BucketCodec::Delta,
+            BucketCodec::DeltaZstd,
+            BucketCodec::DeltaLz4,
+        ] {
+            let header = BucketHeader::new(16, codec, "test")
+                .for_file(crate::bucket_header::RecordType::KmerRead, 2);
+            header
+                .write_file(std::fs::File::create(&filename).unwrap(), |mut buffer| {
+                    let first = KmerRead::new(Kmer::new(1), 1);
+                    let second = KmerRead::new(Kmer::new(2), 2);
+                    if codec.is_delta() {
+                        first.write_delta(&KmerRead::default(), &mut buffer)?;
+                        second.write_delta(&first, &mut buffer)?;
+                    } else {
+                        first.write(&mut buffer)?;
+                        second.write(&mut buffer)?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            let merged = MultiBufReader::<KmerRead>::new(&filenames)
+                .unwrap()
+                .collect::<Result<Vec<_>>>();
+            assert_eq!(merged.unwrap().len(), 2, "{codec}");
+
+            // Corrupt the stored checksum
+            let mut bytes = std::fs::read(&filename).unwrap();
+            let last = bytes.len() - 1;
+            bytes[last] ^= 0xff;
+            std::fs::write(&filename, &bytes).unwrap();
+            let e = MultiBufReader::<KmerRead>::new(&filenames)
+                .and_then(|mbr| mbr.collect::<Result<Vec<_>>>())
+                .unwrap_err();
+            assert!(e.to_string().contains("Checksum mismatch"), "{codec}: {e}");
+        }
+        std::fs::remove_file(&filename).unwrap();
     }
 }
@@ -1,23 +1,80 @@
 use crate::{
-    bucket_list::BucketList, data_bucket::DataBucket, kmer::Kmer, kmer_read::KmerRead,
-    min_max_reads::MinMaxReads, multi_buf_reader::MultiBufReader, read_pair_kmer::ReadPairKmer,
+    bucket_codec::BucketCodec,
+    bucket_header::BucketHeader,
+    bucket_list::BucketList,
+    data_bucket::DataBucket,
+    fastq_reader::{FastqReader, FastqRecord},
+    genomic_region::{
+        fetch_region, is_index_older, open_indexed_bam, resolve_regions, GenomicRegion,
+    },
+    kmer::{BadBasePolicy, Kmer, DEFAULT_BASES_PER_KMER, MAX_BASES_PER_KMER},
+    kmer_read::KmerRead,
+    kmer_sampling::KmerSampling,
+    min_max_reads::MinMaxReads,
+    multi_buf_reader::MultiBufReader,
+    read_grouper_config::{CleanupPolicy, ReadGrouperConfig},
+    read_groups::ReadGroups,
+    read_id_assigner::{read_mate_links, write_mate_links, MateMode, ReadIdAssigner},
+    read_index::ReadIndexWriter,
+    read_pair_kmer::ReadPairKmer,
+    read_stats::ReadStats,
+    sharded_bucket::ShardedBucket,
+    shared_kmer_counter::SharedKmerCounter,
+    union_find::UnionFind,
     ReadId,
 };
 use anyhow::{anyhow, Result};
 use bam::RecordReader;
-use std::{collections::HashMap, path::Path};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use std::{collections::HashMap, fs, path::Path};
 
-const DEFAULT_MIN_BASE_QUALITY: u8 = 20;
-const MAX_BUCKET_SIZE: usize = 1_000_000; // kmer-read-pairs
+pub const DEFAULT_MIN_BASE_QUALITY: u8 = 20;
+pub const MAX_BUCKET_SIZE: usize = 1_000_000; // kmer-read-pairs
+pub const DEFAULT_MERGE_FAN_IN: usize = 256; // bucket files open at once
+pub const DEFAULT_SHARDS: usize = 16;
+/// Secondary, QC-fail, duplicate and supplementary alignments.
+pub const DEFAULT_EXCLUDE_FLAGS: u16 = 0xF00;
+/// Secondary (0x100) and supplementary (0x800) alignments.
+pub const NON_PRIMARY_FLAGS: u16 = 0x900;
 
-type KmerBucket = DataBucket<KmerRead>;
+type KmerBucket = ShardedBucket<KmerRead>;
+
+/// Why a BAM record was not kmerized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Lacks an included flag, or has an excluded one.
+    Flags,
+    /// Mapping quality below the minimum.
+    Mapq,
+}
 type ReadPairKmerBucket = DataBucket<ReadPairKmer>;
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct ReadGrouper {
     bucket_dir: String,
     min_base_quality: u8,
     max_bucket_size: usize,
+    mate_mode: MateMode,
+    include_flags: u16,
+    exclude_flags: u16,
+    min_mapq: u8,
+    regions: Vec<GenomicRegion>,
+    bases_per_kmer: usize,
+    kmer_sampling: KmerSampling,
+    bad_base_policy: BadBasePolicy,
+    bucket_codec: BucketCodec,
+    merge_fan_in: usize,
+    shards: usize,
+    threads: usize,
+    thread_pool: Option<ThreadPool>,
+    cleanup: CleanupPolicy,
+}
+
+/// All defaults, with the bucket dir of `ReadGrouperConfig::default`.
+impl Default for ReadGrouper {
+    fn default() -> Self {
+        Self::new(&ReadGrouperConfig::default().bucket_dir)
+    }
 }
 
 impl ReadGrouper {
@@ -26,48 +83,443 @@ impl ReadGrouper {
             bucket_dir: bucket_dir.to_string(),
             min_base_quality: DEFAULT_MIN_BASE_QUALITY,
             max_bucket_size: MAX_BUCKET_SIZE,
+            mate_mode: MateMode::default(),
+            include_flags: 0,
+            exclude_flags: DEFAULT_EXCLUDE_FLAGS,
+            min_mapq: 0,
+            regions: Vec::new(),
+            bases_per_kmer: DEFAULT_BASES_PER_KMER,
+            kmer_sampling: KmerSampling::default(),
+            bad_base_policy: BadBasePolicy::default(),
+            bucket_codec: BucketCodec::default(),
+            merge_fan_in: DEFAULT_MERGE_FAN_IN,
+            shards: DEFAULT_SHARDS,
+            threads: 0,
+            thread_pool: None,
+            cleanup: CleanupPolicy::default(),
+        }
+    }
+
+    /// Creates a `ReadGrouper` with all settings from `config`, validating them.
+    pub fn from_config(config: &ReadGrouperConfig) -> Result<Self> {
+        let mut rg = Self::new(&config.bucket_dir);
+        rg.set_min_base_quality(config.min_base_quality);
+        rg.set_max_bucket_size(config.max_bucket_size)?;
+        rg.set_bases_per_kmer(config.bases_per_kmer)?;
+        config.kmer_sampling.validate(config.bases_per_kmer)?;
+        rg.set_kmer_sampling(config.kmer_sampling);
+        rg.set_bad_base_policy(config.bad_base_policy);
+        rg.set_mate_mode(config.mate_mode);
+        rg.set_flag_filter(config.include_flags, config.exclude_flags)?;
+        rg.set_min_mapq(config.min_mapq);
+        rg.set_regions(config.regions.clone());
+        rg.set_bucket_codec(config.bucket_codec);
+        rg.set_shards(config.shards)?;
+        rg.set_merge_fan_in(config.merge_fan_in)?;
+        rg.set_threads(config.threads)?;
+        rg.set_cleanup_policy(config.cleanup);
+        Ok(rg)
+    }
+
+    /// The current settings; stored in the `BucketList` of every stage.
+    pub fn config(&self) -> ReadGrouperConfig {
+        ReadGrouperConfig {
+            bucket_dir: self.bucket_dir.clone(),
+            min_base_quality: self.min_base_quality,
+            max_bucket_size: self.max_bucket_size,
+            bases_per_kmer: self.bases_per_kmer,
+            kmer_sampling: self.kmer_sampling,
+            bad_base_policy: self.bad_base_policy,
+            mate_mode: self.mate_mode,
+            include_flags: self.include_flags,
+            exclude_flags: self.exclude_flags,
+            min_mapq: self.min_mapq,
+            regions: self.regions.clone(),
+            bucket_codec: self.bucket_codec,
+            shards: self.shards,
+            merge_fan_in: self.merge_fan_in,
+            threads: self.threads,
+            cleanup: self.cleanup,
+        }
+    }
+
+    /// Sets the number of threads merging shards in parallel; 0 uses one per core.
+    /// Other than 0, this builds a dedicated thread pool, which is reused by every stage.
+    pub fn set_threads(&mut self, threads: usize) -> Result<()> {
+        self.thread_pool = match threads {
+            0 => None, // The global rayon pool
+            _ => Some(ThreadPoolBuilder::new().num_threads(threads).build()?),
+        };
+        self.threads = threads;
+        Ok(())
+    }
+
+    /// Sets whether bucket files are deleted once the next stage has read them.
+    pub fn set_cleanup_policy(&mut self, cleanup: CleanupPolicy) {
+        self.cleanup = cleanup;
+    }
+
+    /// Sets the minimum base quality; kmers covering a base of lower quality are skipped.
+    pub fn set_min_base_quality(&mut self, min_base_quality: u8) {
+        self.min_base_quality = min_base_quality;
+    }
+
+    /// Sets the maximum number of entries held in memory before they are written to disk.
+    pub fn set_max_bucket_size(&mut self, max_bucket_size: usize) -> Result<()> {
+        if max_bucket_size == 0 {
+            return Err(anyhow!("Bucket size must be at least 1"));
+        }
+        self.max_bucket_size = max_bucket_size;
+        Ok(())
+    }
+
+    /// Sets how bucket files are encoded and compressed.
+    pub fn set_bucket_codec(&mut self, bucket_codec: BucketCodec) {
+        self.bucket_codec = bucket_codec;
+    }
+
+    /// Sets the number of kmer shards that are bucketed and merged independently, in parallel.
+    /// The maximum bucket size is split between the shards.
+    pub fn set_shards(&mut self, shards: usize) -> Result<()> {
+        if shards == 0 {
+            return Err(anyhow!("Number of shards must be at least 1"));
+        }
+        self.shards = shards;
+        Ok(())
+    }
+
+    /// Sets the maximum number of bucket files open at once, across all shards merged in
+    /// parallel; at least 2. Each parallel shard merge gets an equal part of it, but no less
+    /// than 2 files. More files are merged in several passes, via intermediate files.
+    pub fn set_merge_fan_in(&mut self, merge_fan_in: usize) -> Result<()> {
+        if merge_fan_in < 2 {
+            return Err(anyhow!(
+                "Merge fan-in must be at least 2, not {merge_fan_in}"
+            ));
+        }
+        self.merge_fan_in = merge_fan_in;
+        Ok(())
+    }
+
+    /// Sets whether a bad base skips only the kmers covering it, or the rest of the read.
+    pub fn set_bad_base_policy(&mut self, bad_base_policy: BadBasePolicy) {
+        self.bad_base_policy = bad_base_policy;
+    }
+
+    /// Sets which kmers of each read are emitted; by default, all of them.
+    pub fn set_kmer_sampling(&mut self, kmer_sampling: KmerSampling) {
+        self.kmer_sampling = kmer_sampling;
+    }
+
+    /// Sets the kmer length k, between 1 and `MAX_BASES_PER_KMER`.
+    pub fn set_bases_per_kmer(&mut self, bases_per_kmer: usize) -> Result<()> {
+        if !(1..=MAX_BASES_PER_KMER).contains(&bases_per_kmer) {
+            return Err(anyhow!(
+                "Kmer length must be between 1 and {MAX_BASES_PER_KMER}, not {bases_per_kmer}"
+            ));
+        }
+        self.bases_per_kmer = bases_per_kmer;
+        Ok(())
+    }
+
+    /// Sets how the mates of paired-end reads are mapped to `ReadId`s.
+    pub fn set_mate_mode(&mut self, mate_mode: MateMode) {
+        self.mate_mode = mate_mode;
+    }
+
+    /// Sets which BAM records are kmerized: those with all `include_flags` and none of
+    /// the `exclude_flags`. Skipped records still get a `ReadId`.
+    /// A flag cannot be both included and excluded, as that would skip every record.
+    pub fn set_flag_filter(&mut self, include_flags: u16, exclude_flags: u16) -> Result<()> {
+        if include_flags & exclude_flags != 0 {
+            return Err(anyhow!(
+                "Flags {:#x} are both included and excluded",
+                include_flags & exclude_flags
+            ));
         }
+        self.include_flags = include_flags;
+        self.exclude_flags = exclude_flags;
+        Ok(())
+    }
+
+    /// Sets the minimum mapping quality of kmerized BAM records.
+    /// Unmapped records have quality 0, so any minimum above 0 skips them.
+    pub fn set_min_mapq(&mut self, min_mapq: u8) {
+        self.min_mapq = min_mapq;
+    }
+
+    /// Restricts `read_bam_file` to records overlapping these regions, via the BAM index.
+    /// `ReadId`s follow the order the records are read in, region by region; a record
+    /// overlapping several regions is read once. Empty to read the whole file.
+    pub fn set_regions(&mut self, regions: Vec<GenomicRegion>) {
+        self.regions = regions;
     }
 
     pub fn read_bam_file(&self, file_path: &str) -> Result<BucketList> {
+        self.kmer_sampling.validate(self.bases_per_kmer)?;
         let file_path = Path::new(file_path);
         let sample_name = Self::file_path_to_sample_name(file_path)?;
-        let mut reader = bam::BamReader::from_path(file_path, 4)?;
-        let mut record = bam::Record::new();
+        let mut parameters = format!(
+            "{} include_flags={:#x} exclude_flags={:#x} min_mapq={}",
+            self.kmer_parameters(file_path),
+            self.include_flags,
+            self.exclude_flags,
+            self.min_mapq
+        );
+        if !self.regions.is_empty() {
+            let regions = self
+                .regions
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>();
+            parameters += &format!(" regions={}", regions.join(","));
+        }
         let mut out_bucket = KmerBucket::new(
-            self.max_bucket_size,
+            self.shards,
+            self.shard_bucket_size(),
             &self.bucket_dir,
             &sample_name,
             "pairs",
+            &BucketHeader::new(self.bases_per_kmer, self.bucket_codec, &parameters),
         );
-        let mut read_number: ReadId = 0;
+        let mut read_ids = ReadIdAssigner::new(self.mate_mode);
+        let mut read_stats = ReadStats::default();
+
+        // With a BAI index, the read index also stores the virtual offset of each read.
+        // Reading the whole file does not need the index, so skips one that may be stale.
+        let bam_path = file_path.display().to_string();
+        let bai_path = format!("{bam_path}.bai");
+        let mut with_offsets = Path::new(&bai_path).exists();
+        if self.regions.is_empty() && with_offsets && is_index_older(&bam_path, &bai_path) {
+            eprintln!("Warning: {bai_path} is older than the BAM file; not storing BAM offsets");
+            with_offsets = false;
+        }
+        if !self.regions.is_empty() && !with_offsets {
+            return Err(anyhow!("Reading regions requires the BAM index {bai_path}"));
+        }
+        let mut read_index =
+            ReadIndexWriter::create(&self.read_index_filename(&sample_name), with_offsets)?;
+        if with_offsets {
+            let mut reader = open_indexed_bam(&bam_path)?;
+            if self.regions.is_empty() {
+                let mut viewer = reader.full();
+                self.read_bam_records(
+                    &mut viewer,
+                    |viewer| Some(viewer.current_offset().raw()),
+                    &mut out_bucket,
+                    &mut read_ids,
+                    &mut read_index,
+                    &mut read_stats,
+                )?;
+            } else {
+                let regions = resolve_regions(&self.regions, reader.header())?;
+                for index in 0..regions.len() {
+                    let mut viewer = fetch_region(&mut reader, &regions, index)?;
+                    self.read_bam_records(
+                        &mut viewer,
+                        |viewer| Some(viewer.current_offset().raw()),
+                        &mut out_bucket,
+                        &mut read_ids,
+                        &mut read_index,
+                        &mut read_stats,
+                    )?;
+                }
+            }
+        } else {
+            let mut reader = bam::BamReader::from_path(file_path, 4)?;
+            self.read_bam_records(
+                &mut reader,
+                |_| None,
+                &mut out_bucket,
+                &mut read_ids,
+                &mut read_index,
+                &mut read_stats,
+            )?;
+        }
+
+        // Write final bucket to disk
+        let filenames = out_bucket.finish()?;
 
+        // Create metadata to return
+        let mut bucket_list = BucketList::new(
+            sample_name,
+            filenames,
+            read_ids.number_of_ids(),
+            self.bases_per_kmer,
+            parameters,
+        );
+        bucket_list.set_read_stats(read_stats);
+        bucket_list.set_read_index(read_index.finish()?);
+        self.write_mate_links(&mut bucket_list, &read_ids)?;
+        bucket_list.set_config(self.config());
+        Ok(bucket_list)
+    }
+
+    /// Kmerizes all records of a BAM reader that pass the flag and mapping quality filters.
+    /// `current_offset` returns the virtual offset of the next record, if the reader knows it.
+    fn read_bam_records<R, F>(
+        &self,
+        reader: &mut R,
+        current_offset: F,
+        out_bucket: &mut KmerBucket,
+        read_ids: &mut ReadIdAssigner,
+        read_index: &mut ReadIndexWriter,
+        read_stats: &mut ReadStats,
+    ) -> Result<()>
+    where
+        R: RecordReader,
+        F: Fn(&R) -> Option<u64>,
+    {
+        let mut record = bam::Record::new();
         loop {
-            match reader.read_into(&mut record) {
-                Ok(true) => {}
-                Ok(false) => break,
-                Err(e) => panic!("{}", e),
+            let offset = current_offset(reader);
+            if !reader.read_into(&mut record)? {
+                break;
+            }
+
+            // Skipped records still get their id, to keep ids in record order
+            let flags = record.flag().0;
+            let primary = flags & NON_PRIMARY_FLAGS == 0;
+            let read_id =
+                Self::assign_read_id(read_ids, read_index, record.name(), primary, offset)?;
+            if let Some(reason) = self.skip_reason(flags, record.mapq()) {
+                read_stats.add_record();
+                match reason {
+                    SkipReason::Flags => read_stats.add_skipped_by_flags(),
+                    SkipReason::Mapq => read_stats.add_skipped_by_mapq(),
+                }
+                continue;
             }
 
-            // Generate and process kmers
             let sequence = record.sequence().to_vec();
             let qualities = record.qualities().raw();
-            let kmers =
-                Kmer::kmers_from_record_incremental(&sequence, qualities, self.min_base_quality);
-            for kmer in kmers {
-                out_bucket.add(KmerRead::new(Kmer::new(kmer), read_number));
-            }
-            read_number += 1;
+            self.add_read_kmers(&sequence, qualities, read_id, out_bucket, read_stats);
+        }
+        Ok(())
+    }
+
+    /// Checks a BAM record against the flag and mapping quality filters.
+    /// Returns why the record is skipped, or `None` if it is kmerized.
+    #[inline(always)]
+    fn skip_reason(&self, flags: u16, mapq: u8) -> Option<SkipReason> {
+        if flags & self.include_flags != self.include_flags || flags & self.exclude_flags != 0 {
+            Some(SkipReason::Flags)
+        } else if mapq < self.min_mapq {
+            Some(SkipReason::Mapq)
+        } else {
+            None
+        }
+    }
+
+    /// Like `read_bam_file`, for plain or gzipped FASTQ.
+    /// `phred_offset` is usually 33; some older Illumina data uses 64.
+    pub fn read_fastq_file(&self, file_path: &str, phred_offset: u8) -> Result<BucketList> {
+        if !self.regions.is_empty() {
+            return Err(anyhow!("Regions need BAM input with a BAM index"));
+        }
+        self.kmer_sampling.validate(self.bases_per_kmer)?;
+        let mut reader = FastqReader::from_path(file_path, phred_offset)?;
+        let sample_name = Self::file_path_to_sample_name(Path::new(file_path))?;
+        let sample_name = sample_name
+            .strip_suffix(".fastq")
+            .or_else(|| sample_name.strip_suffix(".fq"))
+            .unwrap_or(&sample_name)
+            .to_string();
+        let mut record = FastqRecord::default();
+        let parameters = format!(
+            "{} phred_offset={phred_offset}",
+            self.kmer_parameters(Path::new(file_path))
+        );
+        let mut out_bucket = KmerBucket::new(
+            self.shards,
+            self.shard_bucket_size(),
+            &self.bucket_dir,
+            &sample_name,
+            "pairs",
+            &BucketHeader::new(self.bases_per_kmer, self.bucket_codec, &parameters),
+        );
+        let mut read_ids = ReadIdAssigner::new(self.mate_mode);
+        let mut read_stats = ReadStats::default();
+        let mut read_index =
+            ReadIndexWriter::create(&self.read_index_filename(&sample_name), false)?;
+
+        while reader.read_into(&mut record)? {
+            let read_id =
+                Self::assign_read_id(&mut read_ids, &mut read_index, record.name(), true, None)?;
+            self.add_read_kmers(
+                record.sequence(),
+                record.qualities(),
+                read_id,
+                &mut out_bucket,
+                &mut read_stats,
+            );
         }
 
         // Write final bucket to disk
         let filenames = out_bucket.finish()?;
 
         // Create metadata to return
-        let bucket_list = BucketList::new(sample_name, filenames, read_number);
+        let mut bucket_list = BucketList::new(
+            sample_name,
+            filenames,
+            read_ids.number_of_ids(),
+            self.bases_per_kmer,
+            parameters,
+        );
+        bucket_list.set_read_stats(read_stats);
+        bucket_list.set_read_index(read_index.finish()?);
+        self.write_mate_links(&mut bucket_list, &read_ids)?;
+        bucket_list.set_config(self.config());
         Ok(bucket_list)
     }
 
+    /// Assigns the `ReadId` of a record; the first record with a new id adds it to the read index.
+    #[inline(always)]
+    fn assign_read_id(
+        read_ids: &mut ReadIdAssigner,
+        read_index: &mut ReadIndexWriter,
+        name: &[u8],
+        primary: bool,
+        virtual_offset: Option<u64>,
+    ) -> Result<ReadId> {
+        let read_id = read_ids.assign(name, primary);
+        if read_id == read_index.number_of_reads() {
+            read_index.add(name, virtual_offset)?;
+        }
+        Ok(read_id)
+    }
+
+    /// Generates the kmers of a single read and adds them to the bucket.
+    /// Reads shorter than one kmer are only counted; their id stays assigned.
+    #[inline(always)]
+    fn add_read_kmers(
+        &self,
+        sequence: &[u8],
+        qualities: &[u8],
+        read_id: ReadId,
+        out_bucket: &mut KmerBucket,
+        read_stats: &mut ReadStats,
+    ) {
+        read_stats.add_record();
+        if sequence.len() < self.bases_per_kmer {
+            read_stats.add_too_short();
+            return;
+        }
+        let kmers = Kmer::kmers_from_record_incremental(
+            sequence,
+            qualities,
+            self.min_base_quality,
+            self.bases_per_kmer,
+            &self.kmer_sampling,
+            self.bad_base_policy,
+        );
+        for kmer in kmers {
+            let kmer = Kmer::new(kmer);
+            let shard = kmer.shard(out_bucket.number_of_shards());
+            out_bucket.add(shard, KmerRead::new(kmer, read_id));
+        }
+    }
+
     fn process_kmer_grouped_reads(
         &self,
         kmer: &Kmer,
@@ -89,28 +541,101 @@ impl ReadGrouper {
         reads.clear();
     }
 
+    /// Finds read pairs sharing a kmer. Each kmer shard is merged on its own rayon thread,
+    /// and its read pairs are written to the same shard of the returned `BucketList`.
     pub fn process_read_kmer_buckets(
         &self,
         bucket_list: &BucketList,
         min_max: &MinMaxReads,
     ) -> Result<(BucketList, HashMap<usize, usize>)> {
-        let mut mbr: MultiBufReader<KmerRead> = MultiBufReader::new(bucket_list.filenames());
-
         let sample_name = bucket_list.sample_name().to_string();
+        let parameters = format!("{} min_max={min_max:?}", bucket_list.parameters());
+        let header =
+            BucketHeader::new(bucket_list.bases_per_kmer(), self.bucket_codec, &parameters);
+        let shard_fan_in = self.shard_fan_in(bucket_list.shards().len());
+        let process_shards = || {
+            bucket_list
+                .shards()
+                .par_iter()
+                .enumerate()
+                .map(|(shard, filenames)| {
+                    self.process_read_kmer_shard(
+                        shard,
+                        filenames,
+                        shard_fan_in,
+                        &sample_name,
+                        &header,
+                        min_max,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let shard_results = match &self.thread_pool {
+            Some(thread_pool) => thread_pool.install(process_shards)?,
+            None => process_shards()?,
+        };
+        self.cleanup_consumed(bucket_list)?;
+
+        let mut shards = Vec::with_capacity(shard_results.len());
+        let mut stats = HashMap::new();
+        for (filenames, shard_stats) in shard_results {
+            shards.push(filenames);
+            for (reads, count) in shard_stats {
+                *stats.entry(reads).or_insert(0) += count;
+            }
+        }
+
+        let mut pair_bucket_list = BucketList::new(
+            sample_name,
+            shards,
+            bucket_list.number_of_reads(),
+            bucket_list.bases_per_kmer(),
+            parameters,
+        );
+        pair_bucket_list.set_read_stats(bucket_list.read_stats().clone());
+        if let Some(read_index) = bucket_list.read_index() {
+            pair_bucket_list.set_read_index(read_index.to_string());
+        }
+        if let Some(mate_links) = bucket_list.mate_links() {
+            pair_bucket_list.set_mate_links(mate_links.to_string());
+        }
+        pair_bucket_list.set_config(self.config());
+        Ok((pair_bucket_list, stats))
+    }
+
+    /// The fan-in of each of `shards` shard merges, so that the merges running in parallel
+    /// keep at most `merge_fan_in` files open between them.
+    fn shard_fan_in(&self, shards: usize) -> usize {
+        let threads = match &self.thread_pool {
+            Some(thread_pool) => thread_pool.current_num_threads(),
+            None => rayon::current_num_threads(),
+        };
+        (self.merge_fan_in / threads.min(shards).max(1)).max(2)
+    }
+
+    /// Merges the kmer buckets of one shard, and writes the read pairs of each kmer.
+    fn process_read_kmer_shard(
+        &self,
+        shard: usize,
+        filenames: &[String],
+        fan_in: usize,
+        sample_name: &str,
+        header: &BucketHeader,
+        min_max: &MinMaxReads,
+    ) -> Result<(Vec<String>, HashMap<usize, usize>)> {
+        let mbr: MultiBufReader<KmerRead> = MultiBufReader::with_fan_in(filenames, fan_in)?;
         let mut out_bucket = ReadPairKmerBucket::new(
-            self.max_bucket_size,
+            self.shard_bucket_size(),
             &self.bucket_dir,
-            &sample_name,
+            &ShardedBucket::<ReadPairKmer>::shard_sample_name(sample_name, shard),
             "read_pairs",
+            header,
         );
         let mut stats = HashMap::new();
         let mut last_kmer = Kmer::new(0);
         let mut last_reads_ids = Vec::new();
-        while !mbr.is_empty() {
-            let kmer_read = match mbr.next() {
-                Some(kmer_read) => kmer_read,
-                None => break,
-            };
+        for kmer_read in mbr {
+            let kmer_read = kmer_read?;
 
             // Flush reads if new kmer
             if last_kmer != *kmer_read.kmer() {
@@ -132,9 +657,89 @@ impl ReadGrouper {
 
         // Write final bucket to disk
         let filenames = out_bucket.finish()?;
+        Ok((filenames, stats))
+    }
+
+    /// Merges the read pair buckets and groups reads into connected components.
+    /// Two reads are linked if they share at least `min_shared_kmers` kmers.
+    /// Every read gets a group id; reads without any partner form a group of their own.
+    pub fn group_reads(
+        &self,
+        bucket_list: &BucketList,
+        min_shared_kmers: usize,
+    ) -> Result<ReadGroups> {
+        let mbr: MultiBufReader<ReadPairKmer> =
+            MultiBufReader::with_fan_in(&bucket_list.filenames(), self.merge_fan_in)?;
+        let mut union_find = UnionFind::new(bucket_list.number_of_reads());
+        for shared in SharedKmerCounter::new(mbr)? {
+            let (read1, read2, shared_kmers) = shared?;
+            if shared_kmers >= min_shared_kmers {
+                union_find
+                    .union(read1, read2)
+                    .map_err(|e| anyhow!("Invalid read pair buckets: {e}"))?;
+            }
+        }
+        if let Some(mate_links) = bucket_list.mate_links() {
+            for (read1, read2) in read_mate_links(mate_links)? {
+                union_find
+                    .union(read1, read2)
+                    .map_err(|e| anyhow!("Invalid mate links {mate_links}: {e}"))?;
+            }
+        }
+        self.cleanup_consumed(bucket_list)?;
+        Ok(ReadGroups::from_union_find(union_find))
+    }
 
-        let bucket_list = BucketList::new(sample_name, filenames, 0);
-        Ok((bucket_list, stats))
+    /// Deletes the bucket files of a stage that has read them, if the cleanup policy says so.
+    fn cleanup_consumed(&self, bucket_list: &BucketList) -> Result<()> {
+        if self.cleanup == CleanupPolicy::DeleteConsumed {
+            for filename in bucket_list.filenames() {
+                fs::remove_file(&filename)
+                    .map_err(|e| anyhow!("Could not delete bucket file {filename}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// In `Linked` mate mode, writes the mate links next to the buckets and adds them to the list.
+    fn write_mate_links(
+        &self,
+        bucket_list: &mut BucketList,
+        read_ids: &ReadIdAssigner,
+    ) -> Result<()> {
+        if self.mate_mode == MateMode::Linked {
+            let filename = format!(
+                "{}/{}.mate_links",
+                self.bucket_dir,
+                bucket_list.sample_name()
+            );
+            write_mate_links(&filename, read_ids.mate_links())?;
+            bucket_list.set_mate_links(filename);
+        }
+        Ok(())
+    }
+
+    fn read_index_filename(&self, sample_name: &str) -> String {
+        format!("{}/{sample_name}.read_index", self.bucket_dir)
+    }
+
+    /// Bucket size per shard, so all shards together hold at most `max_bucket_size` entries.
+    fn shard_bucket_size(&self) -> usize {
+        (self.max_bucket_size / self.shards).max(1)
+    }
+
+    /// Describes the input file and all settings that affect the kmer buckets.
+    fn kmer_parameters(&self, file_path: &Path) -> String {
+        format!(
+            "input={} min_base_quality={} bases_per_kmer={} kmer_sampling={:?} bad_base_policy={:?} mate_mode={:?} shards={}",
+            file_path.display(),
+            self.min_base_quality,
+            self.bases_per_kmer,
+            self.kmer_sampling,
+            self.bad_base_policy,
+            self.mate_mode,
+            self.shards,
+        )
     }
 
     fn file_path_to_sample_name(file_path: &Path) -> Result<String> {
@@ -146,3 +751,189 @@ impl ReadGrouper {
             .to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_index::ReadIndex, test_bam};
+
+    #[test]
+    fn test_shard_fan_in() {
+        let mut rg = ReadGrouper::default();
+        rg.set_threads(4).unwrap();
+        assert_eq!(rg.shard_fan_in(16), DEFAULT_MERGE_FAN_IN / 4);
+        // Fewer shards than threads share the budget between them
+        assert_eq!(rg.shard_fan_in(2), DEFAULT_MERGE_FAN_IN / 2);
+        assert_eq!(rg.shard_fan_in(1), DEFAULT_MERGE_FAN_IN);
+        rg.set_merge_fan_in(6).unwrap();
+        assert_eq!(rg.shard_fan_in(16), 2);
+    }
+
+    #[test]
+    fn test_skip_reason() {
+        let mut rg = ReadGrouper::default();
+        rg.set_flag_filter(0x1 | 0x2, 0x400).unwrap();
+        rg.set_min_mapq(20);
+        assert_eq!(rg.skip_reason(0x3, 20), None);
+        assert_eq!(rg.skip_reason(0x3 | 0x40, 60), None);
+        assert_eq!(rg.skip_reason(0x1, 60), Some(SkipReason::Flags));
+        assert_eq!(rg.skip_reason(0x0, 60), Some(SkipReason::Flags));
+        assert_eq!(rg.skip_reason(0x3 | 0x400, 60), Some(SkipReason::Flags));
+        assert_eq!(rg.skip_reason(0x3, 19), Some(SkipReason::Mapq));
+        // Flags are checked first
+        assert_eq!(rg.skip_reason(0x400, 0), Some(SkipReason::Flags));
+
+        // The defaults skip secondary, supplementary, QC-failed and duplicate records
+        let rg = ReadGrouper::default();
+        assert_eq!(rg.skip_reason(0x0, 0), None);
+        for flag in [0x100, 0x200, 0x400, 0x800] {
+            assert_eq!(rg.skip_reason(flag, 60), Some(SkipReason::Flags));
+        }
+
+        let mut rg = ReadGrouper::default();
+        assert!(rg.set_flag_filter(0x2, 0x902).is_err());
+        let config = ReadGrouperConfig {
+            include_flags: 0x400,
+            ..Default::default()
+        };
+        assert!(ReadGrouper::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_read_bam_records_without_offsets() {
+        // A reader that cannot tell offsets stores the "no offset" sentinel for every read
+        let bucket_dir = std::env::temp_dir().join("read_grouper_test_without_offsets");
+        let _ = fs::remove_dir_all(&bucket_dir);
+        fs::create_dir_all(&bucket_dir).unwrap();
+        let bucket_dir = bucket_dir.to_str().unwrap();
+        let bam_path = format!("{bucket_dir}/sample.bam");
+        test_bam::write_indexed_bam(
+            &bam_path,
+            &[
+                "read_a\t0\tchr1\t100\t60\t4M\t*\t0\t0\tACGT\tIIII",
+                "read_b\t0\tchr1\t200\t60\t4M\t*\t0\t0\tCCGT\tIIII",
+            ],
+        );
+
+        let rg = ReadGrouper::new(bucket_dir);
+        let mut out_bucket = KmerBucket::new(
+            rg.shards,
+            rg.shard_bucket_size(),
+            bucket_dir,
+            "sample",
+            "pairs",
+            &BucketHeader::new(rg.bases_per_kmer, rg.bucket_codec, "test"),
+        );
+        let mut read_ids = ReadIdAssigner::new(MateMode::Separate);
+        let index_filename = rg.read_index_filename("sample");
+        let mut read_index = ReadIndexWriter::create(&index_filename, true).unwrap();
+        let mut read_stats = ReadStats::default();
+        let mut reader = bam::BamReader::from_path(&bam_path, 0).unwrap();
+        rg.read_bam_records(
+            &mut reader,
+            |_| None,
+            &mut out_bucket,
+            &mut read_ids,
+            &mut read_index,
+            &mut read_stats,
+        )
+        .unwrap();
+        read_index.finish().unwrap();
+
+        let index = ReadIndex::from_file(&index_filename).unwrap();
+        assert_eq!(index.number_of_reads(), 2);
+        assert_eq!(index.name(1), Some("read_b"));
+        assert_eq!(index.virtual_offset(0), None);
+        assert_eq!(index.virtual_offset(1), None);
+        let mut reader = bam::IndexedReader::from_path(&bam_path).unwrap();
+        let e = index
+            .read_record(0, &mut reader, &mut bam::Record::new())
+            .unwrap_err();
+        assert!(e.to_string().contains("No BAM offset"));
+        fs::remove_dir_all(bucket_dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_bam_file_with_stale_index() {
+        // Reading the whole file ignores an index older than the BAM file, and its offsets
+        let bucket_dir = std::env::temp_dir().join("read_grouper_test_stale_index");
+        let _ = fs::remove_dir_all(&bucket_dir);
+        fs::create_dir_all(&bucket_dir).unwrap();
+        let bucket_dir = bucket_dir.to_str().unwrap();
+        let bam_path = format!("{bucket_dir}/sample.bam");
+        test_bam::write_indexed_bam(
+            &bam_path,
+            &[
+                "read_a\t0\tchr1\t100\t60\t4M\t*\t0\t0\tACGT\tIIII",
+                "read_b\t0\tchr1\t200\t60\t4M\t*\t0\t0\tCCGT\tIIII",
+            ],
+        );
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&bam_path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        let rg = ReadGrouper::new(bucket_dir);
+        let bucket_list = rg.read_bam_file(&bam_path).unwrap();
+        assert_eq!(bucket_list.number_of_reads(), 2);
+        let index = ReadIndex::from_file(bucket_list.read_index().unwrap()).unwrap();
+        assert_eq!(index.name(1), Some("read_b"));
+        assert_eq!(index.virtual_offset(0), None);
+        fs::remove_dir_all(bucket_dir).unwrap();
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let bucket_dir = std::env::temp_dir().join("read_grouper_test_pipeline");
+        let _ = fs::remove_dir_all(&bucket_dir);
+        fs::create_dir_all(&bucket_dir).unwrap();
+        let bucket_dir = bucket_dir.to_str().unwrap();
+
+        // Reads 0 and 1 overlap by 100 bases, read 2 shares nothing with them
+        let mut state = 12345u64;
+        let mut random_bases = |length: usize| {
+            (0..length)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    b"ACGT"[(state >> 62) as usize] as char
+                })
+                .collect::<String>()
+        };
+        let genome = random_bases(150);
+        let sequences = [&genome[..120], &genome[20..], &random_bases(120)];
+        let fastq_path = format!("{bucket_dir}/sample.fastq");
+        let fastq = sequences
+            .iter()
+            .enumerate()
+            .map(|(i, sequence)| format!("@r{i}\n{sequence}\n+\n{}\n", "I".repeat(sequence.len())))
+            .collect::<String>();
+        fs::write(&fastq_path, fastq).unwrap();
+
+        let rg = ReadGrouper::new(bucket_dir);
+        let bucket_list = rg.read_fastq_file(&fastq_path, 33).unwrap();
+        assert_eq!(bucket_list.number_of_reads(), 3);
+        let (pair_bucket_list, _) = rg
+            .process_read_kmer_buckets(&bucket_list, &MinMaxReads::default())
+            .unwrap();
+        let read_groups = rg.group_reads(&pair_bucket_list, 2).unwrap();
+        assert_eq!(read_groups.number_of_reads(), 3);
+        assert_eq!(read_groups.number_of_groups(), 2);
+        assert_eq!(read_groups.group_id(0), read_groups.group_id(1));
+        assert_ne!(read_groups.group_id(0), read_groups.group_id(2));
+
+        // Read ids beyond the number of reads are an error, not a panic
+        let short_list = BucketList::new(
+            "sample".to_string(),
+            pair_bucket_list.shards().to_vec(),
+            1,
+            pair_bucket_list.bases_per_kmer(),
+            pair_bucket_list.parameters().to_string(),
+        );
+        let e = rg.group_reads(&short_list, 2).unwrap_err();
+        assert!(e.to_string().contains("out of range"));
+        fs::remove_dir_all(bucket_dir).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{
+    bucket_codec::BucketCodec,
+    genomic_region::GenomicRegion,
+    kmer::{BadBasePolicy, DEFAULT_BASES_PER_KMER},
+    kmer_sampling::KmerSampling,
+    read_grouper::{
+        DEFAULT_EXCLUDE_FLAGS, DEFAULT_MERGE_FAN_IN, DEFAULT_MIN_BASE_QUALITY, DEFAULT_SHARDS,
+        MAX_BUCKET_SIZE,
+    },
+    read_id_assigner::MateMode,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, str::FromStr};
+
+/// What happens to bucket files once the next stage has read them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CleanupPolicy {
+    /// Keep all bucket files, so an identical later run can reuse them.
+    #[default]
+    Keep,
+    /// Delete the input buckets of a stage once it has finished successfully.
+    DeleteConsumed,
+}
+
+impl FromStr for CleanupPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "delete_consumed" => Ok(Self::DeleteConsumed),
+            _ => Err(anyhow!("Unknown cleanup policy '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for CleanupPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Keep => write!(f, "keep"),
+            Self::DeleteConsumed => write!(f, "delete_consumed"),
+        }
+    }
+}
+
+/// All settings of a `ReadGrouper`, loadable from TOML; missing keys get their defaults.
+/// Enum settings use the same strings as on the command line, e.g. `kmer_sampling = "minimizer:10"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadGrouperConfig {
+    /// Directory for the bucket files.
+    pub bucket_dir: String,
+    pub min_base_quality: u8,
+    /// Maximum number of entries held in memory, over all shards.
+    pub max_bucket_size: usize,
+    pub bases_per_kmer: usize,
+    #[serde(with = "as_str")]
+    pub kmer_sampling: KmerSampling,
+    #[serde(with = "as_str")]
+    pub bad_base_policy: BadBasePolicy,
+    #[serde(with = "as_str")]
+    pub mate_mode: MateMode,
+    /// BAM records lacking any of these SAM flags are skipped.
+    pub include_flags: u16,
+    /// BAM records with any of these SAM flags are skipped; by default secondary,
+    /// QC-fail, duplicate and supplementary alignments.
+    pub exclude_flags: u16,
+    /// BAM records with a lower mapping quality are skipped.
+    pub min_mapq: u8,
+    #[serde(with = "as_str")]
+    pub bucket_codec: BucketCodec,
+    pub shards: usize,
+    /// Bucket files open at once, shared by the shards merged in parallel.
+    pub merge_fan_in: usize,
+    /// Threads for merging the shards; 0 uses one per core.
+    pub threads: usize,
+    #[serde(with = "as_str")]
+    pub cleanup: CleanupPolicy,
+    /// Only BAM records overlapping these regions are read; all if empty.
+    pub regions: Vec<GenomicRegion>,
+}
+
+impl Default for ReadGrouperConfig {
+    fn default() -> Self {
+        Self {
+            bucket_dir: std::env::temp_dir()
+                .join("read_grouper")
+                .to_string_lossy()
+                .to_string(),
+            min_base_quality: DEFAULT_MIN_BASE_QUALITY,
+            max_bucket_size: MAX_BUCKET_SIZE,
+            bases_per_kmer: DEFAULT_BASES_PER_KMER,
+            kmer_sampling: KmerSampling::default(),
+            bad_base_policy: BadBasePolicy::default(),
+            mate_mode: MateMode::default(),
+            include_flags: 0,
+            exclude_flags: DEFAULT_EXCLUDE_FLAGS,
+            min_mapq: 0,
+            bucket_codec: BucketCodec::default(),
+            shards: DEFAULT_SHARDS,
+            merge_fan_in: DEFAULT_MERGE_FAN_IN,
+            threads: 0,
+            cleanup: CleanupPolicy::default(),
+            regions: Vec::new(),
+        }
+    }
+}
+
+impl ReadGrouperConfig {
+    pub fn from_toml_file(filename: &str) -> Result<Self> {
+        let config = fs::read_to_string(filename)
+            .map_err(|e| anyhow!("Could not read config {filename}: {e}"))?;
+        toml::from_str(&config).map_err(|e| anyhow!("Invalid config {filename}: {e}"))
+    }
+}
+
+/// (De)serializes a value via its `Display` and `FromStr` implementations.
+mod as_str {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::{fmt::Display, str::FromStr};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toml() {
+        let config: ReadGrouperConfig = toml::from_str(
+            r#"
+            bases_per_kmer = 21
+            kmer_sampling = "minimizer:10"
+            bucket_codec = "delta+zstd"
+            cleanup = "delete_consumed"
+            exclude_flags = 0x900
+
+            [[regions]]
+            reference = "chr6"
+            start = 29941259
+            end = 29945884
+
+            [[regions]]
+            reference = "HLA-A*01:01:01:01"
+            start = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bases_per_kmer, 21);
+        assert_eq!(config.kmer_sampling, KmerSampling::Minimizer { window: 10 });
+        assert_eq!(config.bucket_codec, BucketCodec::DeltaZstd);
+        assert_eq!(config.cleanup, CleanupPolicy::DeleteConsumed);
+        assert_eq!(config.min_base_quality, DEFAULT_MIN_BASE_QUALITY);
+        assert_eq!(config.exclude_flags, 0x900);
+        assert_eq!(config.min_mapq, 0);
+        assert_eq!(
+            config.regions,
+            [
+                GenomicRegion::new("chr6", 29941259, Some(29945884)),
+                GenomicRegion::new("HLA-A*01:01:01:01", 0, None),
+            ]
+        );
+
+        let roundtrip: ReadGrouperConfig =
+            toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(roundtrip, config);
+
+        assert!(toml::from_str::<ReadGrouperConfig>("bucket_codec = \"gzip\"").is_err());
+        assert!(toml::from_str::<ReadGrouperConfig>("kmer_length = 21").is_err());
+    }
+}
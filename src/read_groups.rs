@@ -0,0 +1,137 @@
+use crate::{union_find::UnionFind, ReadId};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+/// The result of the grouping stage: a group id for every read.
+/// Group ids are numbered consecutively from 0, in order of the lowest read id in each group.
+#[derive(Default, Debug)]
+pub struct ReadGroups {
+    group_ids: Vec<ReadId>,
+    group_sizes: Vec<ReadId>,
+}
+
+impl ReadGroups {
+    pub(crate) fn from_union_find(mut union_find: UnionFind) -> Self {
+        let mut root_to_group = HashMap::new();
+        let mut group_ids = Vec::with_capacity(union_find.len());
+        let mut group_sizes = Vec::new();
+        for read_id in 0..union_find.len() as ReadId {
+            let root = union_find.find(read_id);
+            let group_id = *root_to_group.entry(root).or_insert_with(|| {
+                group_sizes.push(0);
+                (group_sizes.len() - 1) as ReadId
+            });
+            group_sizes[group_id as usize] += 1;
+            group_ids.push(group_id);
+        }
+        Self {
+            group_ids,
+            group_sizes,
+        }
+    }
+
+    pub fn number_of_reads(&self) -> usize {
+        self.group_ids.len()
+    }
+
+    pub fn number_of_groups(&self) -> usize {
+        self.group_sizes.len()
+    }
+
+    /// The group of a read, if the read id is known.
+    #[inline(always)]
+    pub fn group_id(&self, read_id: ReadId) -> Option<ReadId> {
+        self.group_ids.get(read_id as usize).copied()
+    }
+
+    /// The number of reads in a group.
+    #[inline(always)]
+    pub fn group_size(&self, group_id: ReadId) -> ReadId {
+        self.group_sizes[group_id as usize]
+    }
+
+    /// Returns a map of group size => number of groups with that size.
+    pub fn size_stats(&self) -> HashMap<usize, usize> {
+        let mut stats = HashMap::new();
+        for size in &self.group_sizes {
+            *stats.entry(*size as usize).or_insert(0) += 1;
+        }
+        stats
+    }
+
+    /// Writes one line per read: read id, group id, group size.
+    pub fn write_tsv(&self, filename: &str) -> Result<()> {
+        let mut file = BufWriter::new(File::create(filename)?);
+        writeln!(file, "read_id\tgroup_id\tgroup_size")?;
+        for (read_id, group_id) in self.group_ids.iter().enumerate() {
+            let group_size = self.group_sizes[*group_id as usize];
+            writeln!(file, "{read_id}\t{group_id}\t{group_size}")?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Reads groups written by `write_tsv`.
+    pub fn read_tsv(filename: &str) -> Result<Self> {
+        let file = File::open(filename)
+            .map_err(|e| anyhow!("Could not open read groups {filename}: {e}"))?;
+        let mut ret = Self::default();
+        for (line_number, line) in BufReader::new(file).lines().enumerate().skip(1) {
+            let line = line?;
+            let mut parts = line.split('\t');
+            let (read_id, group_id) = match (parts.next(), parts.next()) {
+                (Some(read_id), Some(group_id)) => {
+                    (read_id.parse::<ReadId>()?, group_id.parse::<ReadId>()?)
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "{filename}:{}: expected read and group id",
+                        line_number + 1
+                    ))
+                }
+            };
+            if read_id as usize != ret.group_ids.len() {
+                return Err(anyhow!(
+                    "{filename}:{}: expected read id {}, found {read_id}",
+                    line_number + 1,
+                    ret.group_ids.len()
+                ));
+            }
+            if group_id as usize >= ret.group_sizes.len() {
+                ret.group_sizes.resize(group_id as usize + 1, 0);
+            }
+            ret.group_sizes[group_id as usize] += 1;
+            ret.group_ids.push(group_id);
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tsv() {
+        let mut union_find = UnionFind::new(5);
+        union_find.union(0, 3).unwrap();
+        union_find.union(3, 4).unwrap();
+        let read_groups = ReadGroups::from_union_find(union_find);
+        assert_eq!(read_groups.group_id(4), Some(0));
+        assert_eq!(read_groups.group_id(2), Some(2));
+        assert_eq!(read_groups.group_id(5), None);
+        assert_eq!(read_groups.group_size(0), 3);
+
+        let filename = std::env::temp_dir().join("read_grouper_test_groups.tsv");
+        let filename = filename.to_str().unwrap();
+        read_groups.write_tsv(filename).unwrap();
+        let read_back = ReadGroups::read_tsv(filename).unwrap();
+        std::fs::remove_file(filename).unwrap();
+        assert_eq!(read_back.group_ids, read_groups.group_ids);
+        assert_eq!(read_back.group_sizes, read_groups.group_sizes);
+    }
+}
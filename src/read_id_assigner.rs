@@ -0,0 +1,227 @@
+use crate::ReadId;
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    fs::File,
+    io::{BufWriter, Write},
+    str::FromStr,
+};
+
+/// How the two mates of a read pair are mapped to `ReadId`s.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MateMode {
+    /// Every record gets its own id.
+    #[default]
+    Separate,
+    /// Mates share one fragment id, matched by read name anywhere in the input.
+    ByName,
+    /// Mates share one fragment id if they follow each other, as in name-sorted BAM or interleaved FASTQ.
+    Adjacent,
+    /// Every record gets its own id, as with `Separate`, but mates matched by read name are
+    /// recorded as mate links, and end up in the same group.
+    Linked,
+}
+
+impl FromStr for MateMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "separate" => Ok(Self::Separate),
+            "by_name" => Ok(Self::ByName),
+            "adjacent" => Ok(Self::Adjacent),
+            "linked" => Ok(Self::Linked),
+            _ => Err(anyhow!("Unknown mate mode '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for MateMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Separate => write!(f, "separate"),
+            Self::ByName => write!(f, "by_name"),
+            Self::Adjacent => write!(f, "adjacent"),
+            Self::Linked => write!(f, "linked"),
+        }
+    }
+}
+
+/// Hands out sequential `ReadId`s for input records, in input order.
+/// Mates are matched on primary records only. Secondary and supplementary records of a
+/// `ByName` fragment get its id while its mate is still pending, and never pair up themselves.
+#[derive(Debug, Default)]
+pub struct ReadIdAssigner {
+    mode: MateMode,
+    next_id: ReadId,
+    last_name: Vec<u8>,
+    unpaired: HashMap<Vec<u8>, ReadId>,
+    mate_links: Vec<(ReadId, ReadId)>,
+}
+
+impl ReadIdAssigner {
+    pub fn new(mode: MateMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the id for the next input record; `primary` is false for secondary and
+    /// supplementary alignments.
+    #[inline(always)]
+    pub fn assign(&mut self, read_name: &[u8], primary: bool) -> ReadId {
+        let read_name = Self::fragment_name(read_name);
+        match self.mode {
+            MateMode::Separate => self.new_id(),
+            MateMode::ByName if !primary => match self.unpaired.get(read_name) {
+                Some(read_id) => *read_id,
+                None => self.new_id(),
+            },
+            MateMode::ByName => match self.unpaired.remove(read_name) {
+                Some(read_id) => read_id, // Mate seen before; forget it to keep the map small
+                None => {
+                    let read_id = self.new_id();
+                    self.unpaired.insert(read_name.to_vec(), read_id);
+                    read_id
+                }
+            },
+            MateMode::Adjacent => {
+                if self.next_id > 0 && self.last_name == read_name {
+                    self.next_id - 1
+                } else {
+                    self.last_name.clear();
+                    self.last_name.extend_from_slice(read_name);
+                    self.new_id()
+                }
+            }
+            MateMode::Linked => {
+                let read_id = self.new_id();
+                if primary {
+                    match self.unpaired.remove(read_name) {
+                        Some(mate_id) => self.mate_links.push((mate_id, read_id)),
+                        None => {
+                            self.unpaired.insert(read_name.to_vec(), read_id);
+                        }
+                    }
+                }
+                read_id
+            }
+        }
+    }
+
+    /// The (first mate, second mate) ids found in `Linked` mode.
+    pub fn mate_links(&self) -> &[(ReadId, ReadId)] {
+        &self.mate_links
+    }
+
+    /// The number of ids handed out so far.
+    pub fn number_of_ids(&self) -> ReadId {
+        self.next_id
+    }
+
+    #[inline(always)]
+    fn new_id(&mut self) -> ReadId {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// Strips the "/1" and "/2" mate suffixes used in older FASTQ files.
+    #[inline(always)]
+    fn fragment_name(read_name: &[u8]) -> &[u8] {
+        match read_name {
+            [name @ .., b'/', b'1' | b'2'] => name,
+            _ => read_name,
+        }
+    }
+}
+
+/// Writes mate links as pairs of little-endian `ReadId`s.
+pub fn write_mate_links(filename: &str, mate_links: &[(ReadId, ReadId)]) -> Result<()> {
+    let file = File::create(filename)
+        .map_err(|e| anyhow!("Could not create mate links {filename}: {e}"))?;
+    let mut buffer = BufWriter::new(file);
+    for (read1, read2) in mate_links {
+        buffer.write_all(&read1.to_le_bytes())?;
+        buffer.write_all(&read2.to_le_bytes())?;
+    }
+    buffer.flush()?;
+    Ok(())
+}
+
+/// Reads mate links written by `write_mate_links`.
+pub fn read_mate_links(filename: &str) -> Result<Vec<(ReadId, ReadId)>> {
+    let bytes =
+        fs::read(filename).map_err(|e| anyhow!("Could not read mate links {filename}: {e}"))?;
+    if bytes.len() % 8 != 0 {
+        return Err(anyhow!("Invalid mate links {filename}: truncated"));
+    }
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|pair| {
+            let read1 = ReadId::from_le_bytes([pair[0], pair[1], pair[2], pair[3]]);
+            let read2 = ReadId::from_le_bytes([pair[4], pair[5], pair[6], pair[7]]);
+            (read1, read2)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assign() {
+        let names: [&[u8]; 5] = [b"a/1", b"a/2", b"b", b"c", b"b"];
+
+        let mut assigner = ReadIdAssigner::new(MateMode::Separate);
+        let ids = names.map(|name| assigner.assign(name, true));
+        assert_eq!(ids, [0, 1, 2, 3, 4]);
+
+        let mut assigner = ReadIdAssigner::new(MateMode::Adjacent);
+        let ids = names.map(|name| assigner.assign(name, true));
+        assert_eq!(ids, [0, 0, 1, 2, 3]);
+        assert_eq!(assigner.number_of_ids(), 4);
+
+        let mut assigner = ReadIdAssigner::new(MateMode::ByName);
+        let ids = names.map(|name| assigner.assign(name, true));
+        assert_eq!(ids, [0, 0, 1, 2, 1]);
+        assert_eq!(assigner.number_of_ids(), 3);
+        assert!(assigner.unpaired.contains_key(&b"c"[..]));
+        assert_eq!(assigner.unpaired.len(), 1);
+
+        let mut assigner = ReadIdAssigner::new(MateMode::Linked);
+        let ids = names.map(|name| assigner.assign(name, true));
+        assert_eq!(ids, [0, 1, 2, 3, 4]);
+        assert_eq!(assigner.mate_links(), [(0, 1), (2, 4)]);
+    }
+
+    #[test]
+    fn test_assign_non_primary() {
+        // Primary a/1, secondary a/1, primary a/2, supplementary a/2, then a lone secondary
+        let records: [(&[u8], bool); 5] = [
+            (b"a/1", true),
+            (b"a/1", false),
+            (b"a/2", true),
+            (b"a/2", false),
+            (b"b", false),
+        ];
+        let mut assigner = ReadIdAssigner::new(MateMode::ByName);
+        let ids = records.map(|(name, primary)| assigner.assign(name, primary));
+        assert_eq!(ids, [0, 0, 0, 1, 2]);
+        assert!(assigner.unpaired.is_empty());
+
+        let mut assigner = ReadIdAssigner::new(MateMode::Linked);
+        let ids = records.map(|(name, primary)| assigner.assign(name, primary));
+        assert_eq!(ids, [0, 1, 2, 3, 4]);
+        assert_eq!(assigner.mate_links(), [(0, 2)]);
+        assert!(assigner.unpaired.is_empty());
+
+        let filename = std::env::temp_dir().join("read_grouper_test_mate_links");
+        let filename = filename.to_str().unwrap();
+        write_mate_links(filename, assigner.mate_links()).unwrap();
+        assert_eq!(read_mate_links(filename).unwrap(), [(0, 2)]);
+        std::fs::remove_file(filename).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use crate::{
+    bucket_codec::{read_varint, write_varint},
+    ReadId,
+};
+use anyhow::{anyhow, Result};
+use bam::{index::Chunk, index::VirtualOffset, RecordReader};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Seek, Write},
+};
+
+/// Read index layout (all integers little-endian):
+///
+/// | bytes | content                                                    |
+/// |-------|------------------------------------------------------------|
+/// | 4     | magic `RGRI`                                               |
+/// | 2     | format version                                             |
+/// | 1     | 1 if BAM virtual offsets are stored, else 0                |
+/// | ...   | per `ReadId`: varint name length, name, [8 byte offset]    |
+/// | 8     | number of reads                                            |
+pub const MAGIC: [u8; 4] = *b"RGRI";
+pub const FORMAT_VERSION: u16 = 1;
+/// Records `ReadIndex::read_record` reads past a stored offset before giving up.
+/// Only the first record read from a region may follow records skipped as outside the
+/// region, which lie in the same 16 kbp linear index window.
+pub const MAX_SKIPPED_RECORDS: usize = 100_000;
+
+/// Writes the name, and optionally the BAM virtual offset, of each `ReadId` in order.
+/// The file is written to `{filename}.tmp`, and renamed once complete.
+pub struct ReadIndexWriter {
+    filename: String,
+    buffer: BufWriter<File>,
+    with_offsets: bool,
+    number_of_reads: ReadId,
+}
+
+impl ReadIndexWriter {
+    pub fn create(filename: &str, with_offsets: bool) -> Result<Self> {
+        let tmp_filename = format!("{filename}.tmp");
+        let file = File::create(&tmp_filename)
+            .map_err(|e| anyhow!("Could not create read index {tmp_filename}: {e}"))?;
+        let mut buffer = BufWriter::new(file);
+        buffer.write_all(&MAGIC)?;
+        buffer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        buffer.write_all(&[with_offsets as u8])?;
+        Ok(Self {
+            filename: filename.to_string(),
+            buffer,
+            with_offsets,
+            number_of_reads: 0,
+        })
+    }
+
+    pub fn number_of_reads(&self) -> ReadId {
+        self.number_of_reads
+    }
+
+    /// Adds the next `ReadId`. `virtual_offset` is ignored if the index has no offsets.
+    #[inline(always)]
+    pub fn add(&mut self, name: &[u8], virtual_offset: Option<u64>) -> Result<()> {
+        write_varint(&mut self.buffer, name.len() as u64)?;
+        self.buffer.write_all(name)?;
+        if self.with_offsets {
+            self.buffer
+                .write_all(&virtual_offset.unwrap_or(u64::MAX).to_le_bytes())?;
+        }
+        self.number_of_reads += 1;
+        Ok(())
+    }
+
+    /// Completes the file; returns its name.
+    pub fn finish(mut self) -> Result<String> {
+        let tmp_filename = format!("{}.tmp", self.filename);
+        self.buffer
+            .write_all(&(self.number_of_reads as u64).to_le_bytes())?;
+        self.buffer.flush()?;
+        self.buffer.get_ref().sync_all()?;
+        fs::rename(&tmp_filename, &self.filename)
+            .map_err(|e| anyhow!("Could not rename {tmp_filename} to {}: {e}", self.filename))?;
+        Ok(self.filename)
+    }
+}
+
+/// Maps `ReadId`s back to read names and, for indexed BAM input, to records.
+#[derive(Debug, Default)]
+pub struct ReadIndex {
+    names: String,
+    name_ends: Vec<usize>,
+    virtual_offsets: Option<Vec<u64>>,
+}
+
+impl ReadIndex {
+    /// Loads a read index written by `ReadIndexWriter`.
+    pub fn from_file(filename: &str) -> Result<Self> {
+        let bytes =
+            fs::read(filename).map_err(|e| anyhow!("Could not read read index {filename}: {e}"))?;
+        Self::from_bytes(&bytes).map_err(|e| anyhow!("Invalid read index {filename}: {e}"))
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let header_length = MAGIC.len() + 3;
+        if bytes.len() < header_length + 8 || bytes[..4] != MAGIC {
+            return Err(anyhow!("Not a read index"));
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != FORMAT_VERSION {
+            return Err(anyhow!(
+                "Unsupported read index version {version}, expected {FORMAT_VERSION}"
+            ));
+        }
+        let with_offsets = bytes[6] == 1;
+        let (mut records, trailer) =
+            bytes[header_length..].split_at(bytes.len() - header_length - 8);
+        let number_of_reads = u64::from_le_bytes(trailer.try_into()?) as usize;
+        // Every read takes at least a name length byte, and its offset
+        let min_read_size = 1 + if with_offsets { 8 } else { 0 };
+        if number_of_reads > records.len() / min_read_size {
+            return Err(anyhow!(
+                "{number_of_reads} reads cannot fit in {} bytes; truncated or corrupt",
+                records.len()
+            ));
+        }
+
+        let mut ret = Self {
+            names: String::new(),
+            name_ends: Vec::with_capacity(number_of_reads),
+            virtual_offsets: with_offsets.then(|| Vec::with_capacity(number_of_reads)),
+        };
+        let mut name = Vec::new();
+        while !records.is_empty() {
+            name.resize(read_varint(&mut records)? as usize, 0);
+            records.read_exact(&mut name)?;
+            ret.names.push_str(std::str::from_utf8(&name)?);
+            ret.name_ends.push(ret.names.len());
+            if let Some(virtual_offsets) = &mut ret.virtual_offsets {
+                let mut offset = [0; 8];
+                records.read_exact(&mut offset)?;
+                virtual_offsets.push(u64::from_le_bytes(offset));
+            }
+        }
+        if ret.name_ends.len() != number_of_reads {
+            return Err(anyhow!(
+                "Found {} reads, expected {number_of_reads}; truncated or corrupt",
+                ret.name_ends.len()
+            ));
+        }
+        Ok(ret)
+    }
+
+    pub fn number_of_reads(&self) -> ReadId {
+        self.name_ends.len() as ReadId
+    }
+
+    /// The name of the read, as in the input file.
+    pub fn name(&self, read_id: ReadId) -> Option<&str> {
+        let read_id = read_id as usize;
+        let end = *self.name_ends.get(read_id)?;
+        let start = match read_id {
+            0 => 0,
+            _ => self.name_ends[read_id - 1],
+        };
+        Some(&self.names[start..end])
+    }
+
+    /// The BAM virtual file offset of the (first) record of the read, if known.
+    pub fn virtual_offset(&self, read_id: ReadId) -> Option<u64> {
+        let offset = *self.virtual_offsets.as_ref()?.get(read_id as usize)?;
+        (offset != u64::MAX).then_some(offset)
+    }
+
+    /// Reads the (first) record of the read from the BAM file the index was built from.
+    /// When reading regions, the stored offset may precede the record by records that were
+    /// skipped as outside the region, so up to `MAX_SKIPPED_RECORDS` other records are read
+    /// until the name matches.
+    pub fn read_record<R: Read + Seek>(
+        &self,
+        read_id: ReadId,
+        reader: &mut bam::IndexedReader<R>,
+        record: &mut bam::Record,
+    ) -> Result<()> {
+        self.read_record_within(read_id, reader, record, MAX_SKIPPED_RECORDS)
+    }
+
+    /// Reads the record of the read, skipping at most `max_skipped` other records.
+    fn read_record_within<R: Read + Seek>(
+        &self,
+        read_id: ReadId,
+        reader: &mut bam::IndexedReader<R>,
+        record: &mut bam::Record,
+        max_skipped: usize,
+    ) -> Result<()> {
+        let offset = self
+            .virtual_offset(read_id)
+            .ok_or_else(|| anyhow!("No BAM offset known for read {read_id}"))?;
+        let name = self
+            .name(read_id)
+            .ok_or_else(|| anyhow!("Unknown read {read_id}"))?;
+        let chunk = Chunk::new(VirtualOffset::from_raw(offset), VirtualOffset::MAX);
+        let mut viewer = reader.fetch_chunks(vec![chunk]);
+        for _ in 0..=max_skipped {
+            if !viewer.read_into(record)? {
+                break;
+            }
+            if record.name() == name.as_bytes() {
+                return Ok(());
+            }
+        }
+        Err(anyhow!(
+            "No BAM record for read {read_id} within {max_skipped} records after offset {offset}"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read() {
+        let filename = std::env::temp_dir().join("read_grouper_test_read_index");
+        let filename = filename.to_str().unwrap();
+        for with_offsets in [false, true] {
+            let mut writer = ReadIndexWriter::create(filename, with_offsets).unwrap();
+            writer.add(b"read_a", Some(1 << 16)).unwrap();
+            writer.add(b"", None).unwrap();
+            writer.add(b"read_c", Some(5 << 16 | 42)).unwrap();
+            assert_eq!(writer.finish().unwrap(), filename);
+
+            let index = ReadIndex::from_file(filename).unwrap();
+            assert_eq!(index.number_of_reads(), 3);
+            assert_eq!(index.name(0), Some("read_a"));
+            assert_eq!(index.name(1), Some(""));
+            assert_eq!(index.name(2), Some("read_c"));
+            assert_eq!(index.name(3), None);
+            if with_offsets {
+                assert_eq!(index.virtual_offset(0), Some(1 << 16));
+                assert_eq!(index.virtual_offset(1), None);
+                assert_eq!(index.virtual_offset(2), Some(5 << 16 | 42));
+            } else {
+                assert_eq!(index.virtual_offset(0), None);
+            }
+        }
+
+        // Truncate the records
+        let bytes = std::fs::read(filename).unwrap();
+        let mut truncated = bytes[..bytes.len() - 17].to_vec();
+        truncated.extend_from_slice(&bytes[bytes.len() - 8..]);
+        assert!(ReadIndex::from_bytes(&truncated).is_err());
+
+        // A read count far beyond the file size fails before allocating
+        let mut huge = bytes[..bytes.len() - 8].to_vec();
+        huge.extend_from_slice(&u64::MAX.to_le_bytes());
+        let e = ReadIndex::from_bytes(&huge).unwrap_err();
+        assert!(e.to_string().contains("cannot fit"));
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_read_record() {
+        let bam_path = std::env::temp_dir().join("read_grouper_test_read_record.bam");
+        let bam_path = bam_path.to_str().unwrap();
+        crate::test_bam::write_indexed_bam(
+            bam_path,
+            &[
+                "read_a\t0\tchr1\t100\t60\t4M\t*\t0\t0\tACGT\tIIII",
+                "read_b\t0\tchr1\t200\t60\t4M\t*\t0\t0\tCCGT\tIIII",
+                "read_c\t0\tchr1\t300\t60\t4M\t*\t0\t0\tGCGT\tIIII",
+            ],
+        );
+        let mut reader = bam::IndexedReader::build()
+            .modification_time(bam::bam_reader::ModificationTime::Ignore)
+            .from_path(bam_path)
+            .unwrap();
+        let mut offsets = Vec::new();
+        let mut viewer = reader.full();
+        let mut record = bam::Record::new();
+        loop {
+            let offset = viewer.current_offset().raw();
+            if !viewer.read_into(&mut record).unwrap() {
+                break;
+            }
+            offsets.push(offset);
+        }
+        assert_eq!(offsets.len(), 3);
+
+        // read_c points at read_b, as if read_b had been skipped
+        let filename = format!("{bam_path}.read_index");
+        let mut writer = ReadIndexWriter::create(&filename, true).unwrap();
+        writer.add(b"read_a", Some(offsets[0])).unwrap();
+        writer.add(b"read_c", Some(offsets[1])).unwrap();
+        writer.add(b"read_x", Some(offsets[2])).unwrap();
+        writer.add(b"read_y", None).unwrap();
+        writer.finish().unwrap();
+        let index = ReadIndex::from_file(&filename).unwrap();
+
+        index.read_record(0, &mut reader, &mut record).unwrap();
+        assert_eq!(record.name(), b"read_a");
+        assert_eq!(record.start(), 99);
+        index.read_record(1, &mut reader, &mut record).unwrap();
+        assert_eq!(record.name(), b"read_c");
+        let e = index
+            .read_record_within(1, &mut reader, &mut record, 0)
+            .unwrap_err();
+        assert!(e.to_string().contains("within 0 records"));
+        // read_x is not in the file, the scan stops at its end
+        let e = index.read_record(2, &mut reader, &mut record).unwrap_err();
+        assert!(e.to_string().contains("No BAM record"));
+        // or after skipping the maximum number of records
+        writer = ReadIndexWriter::create(&filename, true).unwrap();
+        writer.add(b"read_x", Some(offsets[0])).unwrap();
+        writer.finish().unwrap();
+        let index_x = ReadIndex::from_file(&filename).unwrap();
+        let e = index_x
+            .read_record_within(0, &mut reader, &mut record, 1)
+            .unwrap_err();
+        assert!(e.to_string().contains("within 1 records"));
+        let e = index.read_record(3, &mut reader, &mut record).unwrap_err();
+        assert!(e.to_string().contains("No BAM offset"));
+        assert!(index.read_record(4, &mut reader, &mut record).is_err());
+
+        std::fs::remove_file(&filename).unwrap();
+        std::fs::remove_file(bam_path).unwrap();
+        std::fs::remove_file(format!("{bam_path}.bai")).unwrap();
+    }
+}
@@ -1,16 +1,18 @@
 use crate::{
-    data_bucket::{BucketDataRead, BucketDataWrite},
+    bucket_codec::{read_varint, write_varint},
+    bucket_header::RecordType,
+    data_bucket::{BucketDataRead, BucketDataWrite, BucketRecord},
     kmer::Kmer,
     KmerBits, ReadId,
 };
 use anyhow::Result;
 use std::{
     cmp::Ordering,
-    fs::File,
-    io::{BufReader, BufWriter, Read, Write},
+    io::{Read, Write},
 };
 
-#[derive(Debug)]
+/// Two reads sharing a kmer. Guaranteed to have read1<read2.
+#[derive(Debug, Default, Clone)]
 pub struct ReadPairKmer {
     read1: ReadId,
     read2: ReadId,
@@ -39,26 +41,70 @@ impl ReadPairKmer {
     }
 }
 
+impl BucketRecord for ReadPairKmer {
+    const RECORD_TYPE: RecordType = RecordType::ReadPairKmer;
+}
+
 impl BucketDataWrite for ReadPairKmer {
     #[inline(always)]
-    fn write(&self, buffer: &mut BufWriter<File>) -> Result<()> {
+    fn write<W: Write>(&self, buffer: &mut W) -> Result<()> {
         buffer.write_all(&self.read1().to_le_bytes())?;
         buffer.write_all(&self.read2().to_le_bytes())?;
         buffer.write_all(&self.kmer().to_le_bytes())?;
         Ok(())
     }
+
+    /// Each field as difference to the previous record if all fields before it are the same,
+    /// otherwise as is.
+    #[inline(always)]
+    fn write_delta<W: Write>(&self, previous: &Self, buffer: &mut W) -> Result<()> {
+        let read1_delta = self.read1.wrapping_sub(previous.read1);
+        write_varint(buffer, read1_delta as u64)?;
+        if read1_delta != 0 {
+            write_varint(buffer, self.read2 as u64)?;
+            write_varint(buffer, self.kmer.bits())?;
+            return Ok(());
+        }
+        let read2_delta = self.read2.wrapping_sub(previous.read2);
+        write_varint(buffer, read2_delta as u64)?;
+        if read2_delta != 0 {
+            write_varint(buffer, self.kmer.bits())?;
+            return Ok(());
+        }
+        write_varint(buffer, self.kmer.bits().wrapping_sub(previous.kmer.bits()))?;
+        Ok(())
+    }
 }
 
 impl BucketDataRead for ReadPairKmer {
     #[inline(always)]
-    fn read(&mut self, file_buffer: &mut BufReader<File>) -> Result<()> {
+    fn read<R: Read>(&mut self, file_buffer: &mut R) -> Result<()> {
         let mut buffer = [0; 4];
         file_buffer.read_exact(&mut buffer[..])?;
         self.read1 = ReadId::from_le_bytes(buffer);
         file_buffer.read_exact(&mut buffer[..])?;
         self.read2 = ReadId::from_le_bytes(buffer);
-        file_buffer.read_exact(&mut buffer[..])?;
-        self.kmer = Kmer::new(KmerBits::from_le_bytes(buffer));
+        let mut kmer_buffer = [0; 8];
+        file_buffer.read_exact(&mut kmer_buffer[..])?;
+        self.kmer = Kmer::new(KmerBits::from_le_bytes(kmer_buffer));
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn read_delta<R: Read>(&mut self, file_buffer: &mut R) -> Result<()> {
+        let read1_delta = read_varint(file_buffer)? as ReadId;
+        let read2 = read_varint(file_buffer)? as ReadId;
+        let kmer = read_varint(file_buffer)?;
+        if read1_delta != 0 {
+            self.read1 = self.read1.wrapping_add(read1_delta);
+            self.read2 = read2;
+            self.kmer = Kmer::new(kmer);
+        } else if read2 != 0 {
+            self.read2 = self.read2.wrapping_add(read2);
+            self.kmer = Kmer::new(kmer);
+        } else {
+            self.kmer = Kmer::new(self.kmer.bits().wrapping_add(kmer));
+        }
         Ok(())
     }
 }
@@ -88,3 +134,35 @@ impl PartialEq for ReadPairKmer {
         self.kmer == other.kmer && self.read1 == other.read1 && self.read2 == other.read2
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_roundtrip() {
+        // Covers a change of read1, of read2 only, of the kmer only, and equal consecutive keys
+        let read_pairs = [
+            ReadPairKmer::new(0, 1, &Kmer::new(5)),
+            ReadPairKmer::new(0, 1, &Kmer::new(5)),
+            ReadPairKmer::new(0, 1, &Kmer::new(KmerBits::MAX)),
+            ReadPairKmer::new(0, 7, &Kmer::new(3)),
+            ReadPairKmer::new(2, 3, &Kmer::new(1)),
+            ReadPairKmer::new(ReadId::MAX - 1, ReadId::MAX, &Kmer::new(0)),
+        ];
+        let mut buffer = Vec::new();
+        let mut previous = ReadPairKmer::default();
+        for read_pair in &read_pairs {
+            read_pair.write_delta(&previous, &mut buffer).unwrap();
+            previous = read_pair.clone();
+        }
+
+        let mut reader = &buffer[..];
+        let mut read_pair = ReadPairKmer::default();
+        for expected in &read_pairs {
+            read_pair.read_delta(&mut reader).unwrap();
+            assert_eq!(&read_pair, expected);
+        }
+        assert!(reader.is_empty());
+    }
+}
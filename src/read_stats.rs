@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+
+/// Counts of the input records seen while generating kmers.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadStats {
+    records: u64,
+    too_short: u64,
+    skipped_by_flags: u64,
+    skipped_by_mapq: u64,
+}
+
+impl ReadStats {
+    #[inline(always)]
+    pub fn add_record(&mut self) {
+        self.records += 1;
+    }
+
+    #[inline(always)]
+    pub fn add_too_short(&mut self) {
+        self.too_short += 1;
+    }
+
+    #[inline(always)]
+    pub fn add_skipped_by_flags(&mut self) {
+        self.skipped_by_flags += 1;
+    }
+
+    #[inline(always)]
+    pub fn add_skipped_by_mapq(&mut self) {
+        self.skipped_by_mapq += 1;
+    }
+
+    /// All input records, including skipped ones.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Records skipped because they are shorter than one kmer.
+    pub fn too_short(&self) -> u64 {
+        self.too_short
+    }
+
+    /// BAM records skipped because of their SAM flags.
+    pub fn skipped_by_flags(&self) -> u64 {
+        self.skipped_by_flags
+    }
+
+    /// BAM records skipped because of their mapping quality.
+    pub fn skipped_by_mapq(&self) -> u64 {
+        self.skipped_by_mapq
+    }
+}
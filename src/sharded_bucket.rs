@@ -0,0 +1,51 @@
+use crate::{
+    bucket_header::BucketHeader,
+    data_bucket::{BucketDataWrite, DataBucket},
+};
+use anyhow::Result;
+
+/// Splits data into shards, each written to its own set of `DataBucket` files.
+/// The bucket files of one shard can be merged independently of all other shards.
+#[derive(Debug)]
+pub struct ShardedBucket<T> {
+    shards: Vec<DataBucket<T>>,
+}
+
+impl<T: std::cmp::Ord + BucketDataWrite + Default + Send + 'static> ShardedBucket<T> {
+    /// Files are named like `DataBucket` files, with `_shard{n}` appended to the sample name.
+    /// Every shard has its own writer threads, `shards * DEFAULT_WRITER_THREADS` in total.
+    pub fn new(
+        shards: usize,
+        bucket_size: usize,
+        bucket_dir: &str,
+        sample_name: &str,
+        ending: &str,
+        header: &BucketHeader,
+    ) -> Self {
+        let shards = (0..shards.max(1))
+            .map(|shard| {
+                let sample_name = Self::shard_sample_name(sample_name, shard);
+                DataBucket::new(bucket_size, bucket_dir, &sample_name, ending, header)
+            })
+            .collect();
+        Self { shards }
+    }
+
+    pub fn shard_sample_name(sample_name: &str, shard: usize) -> String {
+        format!("{sample_name}_shard{shard}")
+    }
+
+    pub fn number_of_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    #[inline(always)]
+    pub fn add(&mut self, shard: usize, pair: T) {
+        self.shards[shard].add(pair);
+    }
+
+    /// Writes the remaining data of all shards; returns the filenames of each shard.
+    pub fn finish(&mut self) -> Result<Vec<Vec<String>>> {
+        self.shards.iter_mut().map(|shard| shard.finish()).collect()
+    }
+}
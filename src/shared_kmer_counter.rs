@@ -0,0 +1,135 @@
+use crate::{read_pair_kmer::ReadPairKmer, ReadId};
+use anyhow::Result;
+
+/// Collapses the merged, sorted `ReadPairKmer` stream into one entry per read pair,
+/// with the number of kmers the two reads share.
+pub struct SharedKmerCounter<I: Iterator<Item = Result<ReadPairKmer>>> {
+    read_pairs: I,
+    pending: Option<ReadPairKmer>,
+}
+
+impl<I: Iterator<Item = Result<ReadPairKmer>>> SharedKmerCounter<I> {
+    pub fn new(mut read_pairs: I) -> Result<Self> {
+        let pending = read_pairs.next().transpose()?;
+        Ok(Self {
+            read_pairs,
+            pending,
+        })
+    }
+}
+
+impl<I: Iterator<Item = Result<ReadPairKmer>>> Iterator for SharedKmerCounter<I> {
+    /// (read1, read2, number of shared kmers)
+    type Item = Result<(ReadId, ReadId, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.pending.take()?;
+        let mut shared_kmers = 1;
+        // Pairs are sorted by (read1, read2, kmer), so all kmers of a pair are adjacent
+        for read_pair in self.read_pairs.by_ref() {
+            let read_pair = match read_pair {
+                Ok(read_pair) => read_pair,
+                Err(e) => return Some(Err(e)),
+            };
+            if read_pair.read1() != first.read1() || read_pair.read2() != first.read2() {
+                self.pending = Some(read_pair);
+                break;
+            }
+            shared_kmers += 1;
+        }
+        Some(Ok((first.read1(), first.read2(), shared_kmers)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bucket_codec::BucketCodec, bucket_header::BucketHeader, data_bucket::DataBucket,
+        kmer::Kmer, multi_buf_reader::MultiBufReader,
+    };
+    use anyhow::anyhow;
+
+    fn read_pairs(pairs: &[(ReadId, ReadId, u64)]) -> Vec<Result<ReadPairKmer>> {
+        pairs
+            .iter()
+            .map(|(read1, read2, kmer)| Ok(ReadPairKmer::new(*read1, *read2, &Kmer::new(*kmer))))
+            .collect()
+    }
+
+    #[test]
+    fn test_count_and_threshold() {
+        // (0,1) shares 3 kmers, (0,2) exactly 2, (1,2) only 1
+        let pairs = read_pairs(&[
+            (0, 1, 5),
+            (0, 1, 7),
+            (0, 1, 9),
+            (0, 2, 7),
+            (0, 2, 9),
+            (1, 2, 3),
+        ]);
+        let counted = SharedKmerCounter::new(pairs.into_iter())
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(counted, [(0, 1, 3), (0, 2, 2), (1, 2, 1)]);
+
+        let min_shared_kmers = 2;
+        let grouped = counted
+            .iter()
+            .filter(|(_, _, shared)| *shared >= min_shared_kmers)
+            .map(|(read1, read2, _)| (*read1, *read2))
+            .collect::<Vec<_>>();
+        assert_eq!(grouped, [(0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn test_pair_split_across_runs() {
+        // Bucket size 3 writes two sorted runs; the kmers of pair (3,4) are in both,
+        // and must be counted together once the runs are merged
+        let bucket_dir = std::env::temp_dir().join("read_grouper_test_shared_kmer_counter");
+        let _ = std::fs::remove_dir_all(&bucket_dir);
+        std::fs::create_dir_all(&bucket_dir).unwrap();
+        let header = BucketHeader::new(16, BucketCodec::Delta, "test");
+        let mut bucket = DataBucket::new(
+            3,
+            bucket_dir.to_str().unwrap(),
+            "test",
+            "read_pairs",
+            &header,
+        );
+        let pairs = [
+            (3, 4, 30),
+            (1, 2, 10),
+            (3, 4, 10),
+            (5, 6, 1),
+            (3, 4, 20),
+            (1, 3, 5),
+        ];
+        for read_pair in read_pairs(&pairs) {
+            bucket.add(read_pair.unwrap());
+        }
+        let filenames = bucket.finish().unwrap();
+        assert_eq!(filenames.len(), 2);
+
+        let mbr: MultiBufReader<ReadPairKmer> = MultiBufReader::new(&filenames).unwrap();
+        let counted = SharedKmerCounter::new(mbr)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(counted, [(1, 2, 1), (1, 3, 1), (3, 4, 3), (5, 6, 1)]);
+        std::fs::remove_dir_all(&bucket_dir).unwrap();
+    }
+
+    #[test]
+    fn test_error() {
+        let mut pairs = read_pairs(&[(0, 1, 5)]);
+        pairs.push(Err(anyhow!("broken bucket")));
+        let mut counter = SharedKmerCounter::new(pairs.into_iter()).unwrap();
+        assert!(counter.next().unwrap().is_err());
+        assert!(SharedKmerCounter::new(std::iter::empty())
+            .unwrap()
+            .next()
+            .is_none());
+    }
+}
@@ -0,0 +1,71 @@
+//! Small BAM files for tests.
+
+use bam::{index::VirtualOffset, RecordWriter};
+use std::{fs, io::Write};
+
+/// Length of `chr1`, the only reference of test BAM files.
+pub const REFERENCE_LENGTH: u32 = 100_000;
+
+/// The header of test BAM files, with read group `lane1`.
+pub fn header() -> bam::Header {
+    let mut header = bam::Header::new();
+    header
+        .push_line(&format!("@SQ\tSN:chr1\tLN:{REFERENCE_LENGTH}"))
+        .unwrap();
+    header
+        .push_line("@RG\tID:lane1\tSM:sample\tLB:library")
+        .unwrap();
+    header
+}
+
+/// A record from a SAM line, e.g. `r1\t0\tchr1\t100\t60\t4M\t*\t0\t0\tACGT\tIIII`.
+pub fn record(sam_line: &str) -> bam::Record {
+    let mut record = bam::Record::new();
+    record.fill_from_sam(sam_line, &header()).unwrap();
+    record
+}
+
+/// Writes the records to `path`, with a BAI index at `{path}.bai`.
+/// The records must be sorted by position. The index has a single bin and window holding
+/// all records, which is enough for fetching regions of `chr1`.
+pub fn write_indexed_bam(path: &str, sam_lines: &[&str]) {
+    let mut writer = bam::BamWriter::from_path(path, header()).unwrap();
+    for sam_line in sam_lines {
+        writer.write(&record(sam_line)).unwrap();
+    }
+    writer.finish().unwrap();
+
+    // Without bins, the reader starts right after the header, at the first record
+    let bai_path = format!("{path}.bai");
+    write_bai(&bai_path, None);
+    let mut reader = bam::IndexedReader::build()
+        .modification_time(bam::bam_reader::ModificationTime::Ignore)
+        .from_path(path)
+        .unwrap();
+    let first_record = reader.full().current_offset();
+    write_bai(&bai_path, Some(first_record));
+}
+
+/// Writes an index with one reference, optionally with one chunk from `first_record` to the end.
+fn write_bai(bai_path: &str, first_record: Option<VirtualOffset>) {
+    let mut bai = Vec::new();
+    bai.write_all(b"BAI\x01").unwrap();
+    bai.write_all(&1i32.to_le_bytes()).unwrap();
+    match first_record {
+        Some(offset) => {
+            bai.write_all(&1i32.to_le_bytes()).unwrap(); // Bins
+            bai.write_all(&0u32.to_le_bytes()).unwrap();
+            bai.write_all(&1i32.to_le_bytes()).unwrap(); // Chunks
+            bai.write_all(&offset.raw().to_le_bytes()).unwrap();
+            bai.write_all(&VirtualOffset::MAX.raw().to_le_bytes())
+                .unwrap();
+            bai.write_all(&1i32.to_le_bytes()).unwrap(); // Windows
+            bai.write_all(&offset.raw().to_le_bytes()).unwrap();
+        }
+        None => {
+            bai.write_all(&0i32.to_le_bytes()).unwrap();
+            bai.write_all(&0i32.to_le_bytes()).unwrap();
+        }
+    }
+    fs::write(bai_path, bai).unwrap();
+}
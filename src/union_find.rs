@@ -0,0 +1,76 @@
+use crate::ReadId;
+use anyhow::{anyhow, Result};
+
+/// Disjoint-set forest over all read ids, with union by size and path halving.
+/// Only the parent and size arrays are kept in memory; the read pairs themselves
+/// are streamed from the bucket files.
+#[derive(Debug)]
+pub struct UnionFind {
+    parents: Vec<ReadId>,
+    sizes: Vec<ReadId>,
+}
+
+impl UnionFind {
+    pub fn new(number_of_reads: ReadId) -> Self {
+        Self {
+            parents: (0..number_of_reads).collect(),
+            sizes: vec![1; number_of_reads as usize],
+        }
+    }
+
+    #[inline(always)]
+    pub fn find(&mut self, mut read_id: ReadId) -> ReadId {
+        while self.parents[read_id as usize] != read_id {
+            let grandparent = self.parents[self.parents[read_id as usize] as usize];
+            self.parents[read_id as usize] = grandparent;
+            read_id = grandparent;
+        }
+        read_id
+    }
+
+    /// Joins the sets of both reads; fails if either read id is out of range.
+    #[inline(always)]
+    pub fn union(&mut self, read1: ReadId, read2: ReadId) -> Result<()> {
+        let number_of_reads = self.len();
+        if read1 as usize >= number_of_reads || read2 as usize >= number_of_reads {
+            return Err(anyhow!(
+                "Read pair ({read1}, {read2}) is out of range for {number_of_reads} reads"
+            ));
+        }
+        let mut root1 = self.find(read1);
+        let mut root2 = self.find(read2);
+        if root1 == root2 {
+            return Ok(());
+        }
+        if self.sizes[root1 as usize] < self.sizes[root2 as usize] {
+            std::mem::swap(&mut root1, &mut root2);
+        }
+        self.parents[root2 as usize] = root1;
+        self.sizes[root1 as usize] += self.sizes[root2 as usize];
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.parents.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_union_find() {
+        let mut uf = UnionFind::new(6);
+        uf.union(0, 1).unwrap();
+        uf.union(3, 4).unwrap();
+        uf.union(1, 4).unwrap();
+        assert_eq!(uf.find(0), uf.find(3));
+        assert_eq!(uf.find(1), uf.find(4));
+        assert_ne!(uf.find(0), uf.find(2));
+        assert_ne!(uf.find(5), uf.find(2));
+        assert_eq!(uf.len(), 6);
+        assert!(uf.union(5, 6).is_err());
+        assert!(uf.union(6, 0).is_err());
+    }
+}